@@ -0,0 +1,592 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use hmac::{ Hmac, Mac };
+use md5::Md5;
+use sha2::{ Digest, Sha256 };
+use rand::Rng;
+use crate::def::*;
+use crate::message::*;
+
+// the Basic (rfc 7617), Bearer (rfc 6750) and Digest (rfc 7616) authorization schemes
+
+pub const DEFAULT_NONCE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+const CNONCE_LENGTH: usize = 16;
+const SECRET_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    Basic,
+    Bearer,
+    Digest
+}
+
+impl Scheme {
+    pub fn parse(scheme: &str) -> Option<Self> {
+        match scheme.to_lowercase().as_str() {
+            "basic" => Some(Self::Basic),
+            "bearer" => Some(Self::Bearer),
+            "digest" => Some(Self::Digest),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Basic => "Basic",
+            Self::Bearer => "Bearer",
+            Self::Digest => "Digest"
+        }
+    }
+}
+
+// digest credentials are only sent in answer to a challenge, see DigestChallenge::answer
+#[derive(Clone, Debug, PartialEq)]
+pub enum Credentials {
+    Basic { user: String, password: String },
+    Bearer(String),
+    Digest { user: String, password: String }
+}
+
+impl Credentials {
+    pub fn basic<U: Into<String>, P: Into<String>>(user: U, password: P) -> Self {
+        Self::Basic { user: user.into(), password: password.into() }
+    }
+
+    pub fn bearer<T: Into<String>>(token: T) -> Self {
+        Self::Bearer(token.into())
+    }
+
+    pub fn digest<U: Into<String>, P: Into<String>>(user: U, password: P) -> Self {
+        Self::Digest { user: user.into(), password: password.into() }
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        match self {
+            Self::Basic { .. } => Scheme::Basic,
+            Self::Bearer(_) => Scheme::Bearer,
+            Self::Digest { .. } => Scheme::Digest
+        }
+    }
+
+    // the value of an Authorization header
+    pub fn parse(authorization: &str) -> Option<Self> {
+        let (scheme, parameters) = authorization.trim().split_once(' ')?;
+        let parameters = parameters.trim();
+        match Scheme::parse(scheme)? {
+            Scheme::Basic => {
+                let decoded = String::from_utf8(base64::decode(parameters).ok()?).ok()?;
+                let (user, password) = decoded.split_once(':')?;
+                Some(Self::basic(user, password))
+            }
+            Scheme::Bearer if !parameters.is_empty() => Some(Self::bearer(parameters)),
+            // the password never travels, see DigestAuthorization
+            Scheme::Bearer | Scheme::Digest => None
+        }
+    }
+
+    // None for digest credentials
+    pub fn value(&self) -> Option<String> {
+        match self {
+            Self::Basic { user, password } => Some(format!("Basic {}", base64::encode(format!("{}:{}", user, password)))),
+            Self::Bearer(token) => Some(format!("Bearer {}", token)),
+            Self::Digest { .. } => None
+        }
+    }
+
+    pub fn header(&self) -> Option<Header<'static>> {
+        self.value().map(|value| Header::new("Authorization", value))
+    }
+}
+
+impl<'a> Request<'a> {
+    pub fn authorize(&mut self, credentials: &Credentials) {
+        if let Some(header) = credentials.header() {
+            self.message.headers.add(header);
+        }
+    }
+
+    pub fn credentials(&self) -> Option<Credentials> {
+        self.message.headers.get("authorization").and_then(Credentials::parse)
+    }
+}
+
+// why the credentials were refused, bearer challenges carry it as the error parameter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Refusal {
+    Missing,
+    Invalid,
+    // a digest nonce that is too old, the client may retry without asking the user
+    Stale
+}
+
+// lets requests through when the validator accepts their credentials, everything else gets a 401
+// with a challenge for every scheme the guard takes
+pub struct Guard<V: Fn(&Credentials) -> bool> {
+    pub realm: String,
+    pub schemes: Vec<Scheme>,
+    validate: V
+}
+
+impl<V: Fn(&Credentials) -> bool> Guard<V> {
+    pub fn new<S: Into<String>>(realm: S, schemes: Vec<Scheme>, validate: V) -> Self {
+        Self { realm: realm.into(), schemes, validate }
+    }
+
+    pub fn check(&self, request: &Request) -> Result<Credentials, Refusal> {
+        let credentials = request.credentials().filter(|c| self.schemes.contains(&c.scheme())).ok_or(Refusal::Missing)?;
+        if (self.validate)(&credentials) { Ok(credentials) } else { Err(Refusal::Invalid) }
+    }
+
+    pub fn challenge(&self, refusal: Refusal) -> Response<'static> {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut response = Response::new();
+        response.status = Status::Unauthorized;
+        for scheme in &self.schemes {
+            let challenge = match (scheme, refusal) {
+                (Scheme::Basic, _) => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+                (Scheme::Bearer, Refusal::Missing) => format!("Bearer realm=\"{}\"", realm),
+                (Scheme::Bearer, _) => format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm),
+                // digest challenges need the nonces of a DigestGuard
+                (Scheme::Digest, _) => continue
+            };
+            response.message.headers.append(Header::new("WWW-Authenticate", challenge));
+        }
+        response
+    }
+
+    // the handler only runs for accepted credentials
+    pub fn handle<'r, 'a, R, F>(&self, request: R, handler: F) -> Option<Response<'a>>
+    where R: Borrow<Request<'r>>, F: FnOnce(R, Credentials) -> Option<Response<'a>> {
+        match self.check(request.borrow()) {
+            Ok(credentials) => handler(request, credentials),
+            Err(refusal) => Some(self.challenge(refusal))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha256
+}
+
+impl Algorithm {
+    pub fn parse(algorithm: &str) -> Option<Self> {
+        match algorithm.to_uppercase().as_str() {
+            "MD5" => Some(Self::Md5),
+            "SHA-256" => Some(Self::Sha256),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Md5 => "MD5",
+            Self::Sha256 => "SHA-256"
+        }
+    }
+
+    fn hash(&self, data: &str) -> String {
+        match self {
+            Self::Md5 => hex(&Md5::digest(data.as_bytes())),
+            Self::Sha256 => hex(&Sha256::digest(data.as_bytes()))
+        }
+    }
+}
+
+// a WWW-Authenticate: Digest challenge
+#[derive(Clone, Debug, PartialEq)]
+pub struct DigestChallenge {
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: Algorithm,
+    // qop=auth is answered, without any qop the rfc 2069 form is used
+    pub qop: Vec<String>,
+    pub stale: bool
+}
+
+impl DigestChallenge {
+    // challenges with an algorithm we don't know are skipped
+    pub fn parse(www_authenticate: &str) -> Option<Self> {
+        let (scheme, parameters) = www_authenticate.trim().split_once(' ')?;
+        if Scheme::parse(scheme)? != Scheme::Digest { return None }
+
+        let parameters = parse_parameters(parameters);
+        let algorithm = match parameters.get("algorithm") {
+            Some(algorithm) => Algorithm::parse(algorithm)?,
+            None => Algorithm::Md5
+        };
+        Some(Self {
+            realm: parameters.get("realm")?.clone(),
+            nonce: parameters.get("nonce")?.clone(),
+            opaque: parameters.get("opaque").cloned(),
+            algorithm,
+            qop: parameters.get("qop").map(|qop| qop.split(',').map(|q| q.trim().to_lowercase()).collect()).unwrap_or_default(),
+            stale: parameters.get("stale").is_some_and(|stale| stale.eq_ignore_ascii_case("true"))
+        })
+    }
+
+    pub fn construct(&self) -> String {
+        let mut challenge = format!("Digest realm={}, nonce={}", quote(&self.realm), quote(&self.nonce));
+        if let Some(opaque) = &self.opaque {
+            challenge.push_str(&format!(", opaque={}", quote(opaque)));
+        }
+        challenge.push_str(&format!(", algorithm={}", self.algorithm.as_str()));
+        if !self.qop.is_empty() {
+            challenge.push_str(&format!(", qop={}", quote(&self.qop.join(", "))));
+        }
+        if self.stale {
+            challenge.push_str(", stale=true");
+        }
+        challenge
+    }
+
+    // count is the nonce count, starting at 1 and going up with every request using the nonce
+    pub fn answer(&self, user: &str, password: &str, method: Method, uri: &str, count: u32) -> DigestAuthorization {
+        let auth = self.qop.is_empty() || self.qop.iter().any(|qop| qop == "auth");
+        let (qop, cnonce) = if !self.qop.is_empty() && auth {
+            let cnonce: [u8; CNONCE_LENGTH] = rand::thread_rng().gen();
+            (Some("auth".to_string()), Some(hex(&cnonce)))
+        } else {
+            (None, None)
+        };
+
+        let mut authorization = DigestAuthorization {
+            user: user.to_string(),
+            realm: self.realm.clone(),
+            nonce: self.nonce.clone(),
+            uri: uri.to_string(),
+            response: String::new(),
+            algorithm: self.algorithm,
+            opaque: self.opaque.clone(),
+            qop,
+            count: Some(count),
+            cnonce
+        };
+        if authorization.qop.is_none() { authorization.count = None }
+        authorization.response = authorization.expected(password, method);
+        authorization
+    }
+}
+
+// the parameters of an Authorization: Digest header
+#[derive(Clone, Debug, PartialEq)]
+pub struct DigestAuthorization {
+    pub user: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: Algorithm,
+    pub opaque: Option<String>,
+    pub qop: Option<String>,
+    pub count: Option<u32>,
+    pub cnonce: Option<String>
+}
+
+impl DigestAuthorization {
+    pub fn parse(authorization: &str) -> Option<Self> {
+        let (scheme, parameters) = authorization.trim().split_once(' ')?;
+        if Scheme::parse(scheme)? != Scheme::Digest { return None }
+
+        let parameters = parse_parameters(parameters);
+        let algorithm = match parameters.get("algorithm") {
+            Some(algorithm) => Algorithm::parse(algorithm)?,
+            None => Algorithm::Md5
+        };
+        let count = match parameters.get("nc") {
+            Some(count) => Some(u32::from_str_radix(count, 16).ok()?),
+            None => None
+        };
+        Some(Self {
+            user: parameters.get("username")?.clone(),
+            realm: parameters.get("realm")?.clone(),
+            nonce: parameters.get("nonce")?.clone(),
+            uri: parameters.get("uri")?.clone(),
+            response: parameters.get("response")?.to_lowercase(),
+            algorithm,
+            opaque: parameters.get("opaque").cloned(),
+            qop: parameters.get("qop").cloned(),
+            count,
+            cnonce: parameters.get("cnonce").cloned()
+        })
+    }
+
+    pub fn construct(&self) -> String {
+        let mut authorization = format!("Digest username={}, realm={}, nonce={}, uri={}, algorithm={}, response={}",
+            quote(&self.user), quote(&self.realm), quote(&self.nonce), quote(&self.uri), self.algorithm.as_str(), quote(&self.response));
+        if let Some(opaque) = &self.opaque {
+            authorization.push_str(&format!(", opaque={}", quote(opaque)));
+        }
+        if let (Some(qop), Some(count), Some(cnonce)) = (&self.qop, self.count, &self.cnonce) {
+            authorization.push_str(&format!(", qop={}, nc={:08x}, cnonce={}", qop, count, quote(cnonce)));
+        }
+        authorization
+    }
+
+    pub fn header(&self) -> Header<'static> {
+        Header::new("Authorization", self.construct())
+    }
+
+    // the response the password would give, rfc 7616 section 3.4.1
+    pub fn expected(&self, password: &str, method: Method) -> String {
+        let hash = |data: String| self.algorithm.hash(&data);
+        let secret = hash(format!("{}:{}:{}", self.user, self.realm, password));
+        let digest = hash(format!("{}:{}", method, self.uri));
+        match (&self.qop, self.count, &self.cnonce) {
+            (Some(qop), Some(count), Some(cnonce)) => hash(format!("{}:{}:{:08x}:{}:{}:{}", secret, self.nonce, count, cnonce, qop, digest)),
+            _ => hash(format!("{}:{}:{}", secret, self.nonce, digest))
+        }
+    }
+}
+
+// issues digest challenges and checks the answers, the lookup gives the password of a user
+// nonces carry their time and a mac so they need no storage, only the last count of each one is kept
+pub struct DigestGuard<L: Fn(&str) -> Option<String>> {
+    pub realm: String,
+    pub algorithm: Algorithm,
+    pub lifetime: Duration,
+    secret: [u8; SECRET_LENGTH],
+    counts: Mutex<HashMap<String, u32>>,
+    lookup: L
+}
+
+impl<L: Fn(&str) -> Option<String>> DigestGuard<L> {
+    pub fn new<S: Into<String>>(realm: S, algorithm: Algorithm, lookup: L) -> Self {
+        Self {
+            realm: realm.into(),
+            algorithm,
+            lifetime: DEFAULT_NONCE_LIFETIME,
+            secret: rand::thread_rng().gen(),
+            counts: Mutex::new(HashMap::new()),
+            lookup
+        }
+    }
+
+    fn nonce(&self, issued: u64) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.secret).expect("hmac takes keys of any length");
+        mac.update(issued.to_string().as_bytes());
+        format!("{}-{}", issued, hex(&mac.finalize().into_bytes()))
+    }
+
+    // Err(true) for nonces we made that are too old
+    fn verify_nonce(&self, nonce: &str) -> Result<u64, bool> {
+        let issued = nonce.split_once('-').and_then(|(issued, _)| issued.parse::<u64>().ok()).ok_or(false)?;
+        if !equal(self.nonce(issued).as_bytes(), nonce.as_bytes()) { return Err(false) }
+        if now() > issued + self.lifetime.as_secs() { return Err(true) }
+        Ok(issued)
+    }
+
+    // the user the request was authorized for
+    pub fn check(&self, request: &Request) -> Result<String, Refusal> {
+        let authorization = request.message.headers.get("authorization").and_then(DigestAuthorization::parse).ok_or(Refusal::Missing)?;
+        if authorization.realm != self.realm || authorization.algorithm != self.algorithm { Err(Refusal::Invalid)? }
+        if authorization.uri != request.target.to_string() { Err(Refusal::Invalid)? }
+        if authorization.qop.as_deref() != Some("auth") { Err(Refusal::Invalid)? }
+        self.verify_nonce(&authorization.nonce).map_err(|stale| if stale { Refusal::Stale } else { Refusal::Invalid })?;
+
+        let password = (self.lookup)(&authorization.user).ok_or(Refusal::Invalid)?;
+        if !equal(authorization.expected(&password, request.method).as_bytes(), authorization.response.as_bytes()) { Err(Refusal::Invalid)? }
+
+        // every count is used once, so a captured request can't be replayed
+        let count = authorization.count.ok_or(Refusal::Invalid)?;
+        let mut counts = self.counts.lock().unwrap();
+        if counts.get(&authorization.nonce).is_some_and(|last| count <= *last) { Err(Refusal::Invalid)? }
+        counts.retain(|nonce, _| self.verify_nonce(nonce).is_ok());
+        counts.insert(authorization.nonce, count);
+        Ok(authorization.user)
+    }
+
+    pub fn challenge(&self, refusal: Refusal) -> Response<'static> {
+        let challenge = DigestChallenge {
+            realm: self.realm.clone(),
+            nonce: self.nonce(now()),
+            opaque: None,
+            algorithm: self.algorithm,
+            qop: vec!["auth".to_string()],
+            stale: refusal == Refusal::Stale
+        };
+        let mut response = Response::new();
+        response.status = Status::Unauthorized;
+        response.message.headers.append(Header::new("WWW-Authenticate", challenge.construct()));
+        response
+    }
+
+    pub fn handle<'r, 'a, R, F>(&self, request: R, handler: F) -> Option<Response<'a>>
+    where R: Borrow<Request<'r>>, F: FnOnce(R, String) -> Option<Response<'a>> {
+        match self.check(request.borrow()) {
+            Ok(user) => handler(request, user),
+            Err(refusal) => Some(self.challenge(refusal))
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// compares in the same time wherever the first difference is
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// comma separated name=value pairs, values may be quoted strings with commas and escapes in them
+fn parse_parameters(parameters: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut chars = parameters.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) { chars.next(); }
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if name.is_empty() { break }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) { chars.next(); }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c)
+                }
+            }
+            while chars.peek().is_some_and(|c| *c != ',') { chars.next(); }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect::<String>().trim().to_string();
+        }
+        parsed.insert(name.trim().to_lowercase(), value);
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::*;
+
+    #[test]
+    fn parse_credentials() {
+        let basic = Credentials::parse("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==").unwrap();
+        assert_eq!(basic, Credentials::basic("Aladdin", "open sesame"));
+        assert_eq!(basic.value().as_deref(), Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="));
+        assert_eq!(Credentials::parse("bearer mF_9.B5f-4.1JqM"), Some(Credentials::bearer("mF_9.B5f-4.1JqM")));
+        assert_eq!(Credentials::parse("Basic not base64"), None);
+        assert_eq!(Credentials::parse("Negotiate abc"), None);
+    }
+
+    fn protected(request: Request) -> Option<Response> {
+        let guard = Guard::new("api", vec![Scheme::Basic, Scheme::Bearer], |credentials| match credentials {
+            Credentials::Basic { user, password } => user == "admin" && password == "secret",
+            Credentials::Bearer(token) => token == "token",
+            _ => false
+        });
+        guard.handle(request, |_, credentials| {
+            let mut response = Response::new();
+            response.message.payload = Payload::Identity(credentials.scheme().as_str().as_bytes().to_vec().into());
+            Some(response)
+        })
+    }
+
+    #[test]
+    fn guard_requests() {
+        let (listener, port) = crate::tests::local();
+        Http11::listen_on(listener, &protected).unwrap();
+        let uri = format!("http://127.0.0.1:{}/", port);
+
+        let response = Http::get(&uri).unwrap();
+        assert!(matches!(response.status, Status::Unauthorized));
+        let challenges: Vec<_> = response.message.headers.get_all("www-authenticate").collect();
+        assert_eq!(challenges, vec!["Basic realm=\"api\", charset=\"UTF-8\"", "Bearer realm=\"api\""]);
+
+        // credentials for the host go with every request to it
+        let mut client = Http::new();
+        client.credentials.insert("127.0.0.1".to_string(), Credentials::basic("admin", "secret"));
+        assert_eq!(client.fetch(&uri).unwrap().message.payload.raw(), b"Basic");
+
+        // the ones set on the request win
+        let mut request = Request::new();
+        request.authorize(&Credentials::bearer("expired"));
+        let response = client.send(&uri, &request).unwrap();
+        assert!(matches!(response.status, Status::Unauthorized));
+        assert!(response.message.headers.get_all("www-authenticate").any(|c| c.ends_with("error=\"invalid_token\"")));
+    }
+
+    #[test]
+    fn digest_response() {
+        // the example of rfc 7616 section 3.9.1
+        let mut challenge = DigestChallenge::parse("Digest realm=\"http-auth@example.org\", qop=\"auth, auth-int\", algorithm=MD5, \
+            nonce=\"7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v\", opaque=\"FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS\"").unwrap();
+        assert_eq!(challenge.qop, vec!["auth", "auth-int"]);
+        assert_eq!(DigestChallenge::parse(&challenge.construct()), Some(challenge.clone()));
+
+        let mut authorization = challenge.answer("Mufasa", "Circle of Life", Method::GET, "/dir/index.html", 1);
+        authorization.cnonce = Some("f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ".to_string());
+        assert_eq!(authorization.expected("Circle of Life", Method::GET), "8ca523f5e9506fed4657c9700eebdbec");
+        authorization.algorithm = Algorithm::Sha256;
+        assert_eq!(authorization.expected("Circle of Life", Method::GET), "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1");
+
+        authorization.response = authorization.expected("Circle of Life", Method::GET);
+        let parsed = DigestAuthorization::parse(&authorization.construct()).unwrap();
+        assert_eq!(parsed, authorization);
+        assert!(authorization.construct().contains("nc=00000001"));
+
+        challenge.algorithm = Algorithm::Sha256;
+        challenge.realm = "quoted \"realm\", with comma".to_string();
+        assert_eq!(DigestChallenge::parse(&challenge.construct()).unwrap().realm, challenge.realm);
+        assert_eq!(DigestChallenge::parse("Digest realm=\"a\", nonce=\"b\", algorithm=SHA-512"), None);
+    }
+
+    lazy_static::lazy_static! {
+        static ref DIGEST: DigestGuard<fn(&str) -> Option<String>> = DigestGuard::new("appliance", Algorithm::Sha256, |user| {
+            if user == "admin" { Some("secret".to_string()) } else { None }
+        });
+    }
+
+    fn appliance(request: Request) -> Option<Response> {
+        DIGEST.handle(request, |_, user| {
+            let mut response = Response::new();
+            response.message.payload = Payload::Identity(user.into_bytes().into());
+            Some(response)
+        })
+    }
+
+    #[test]
+    fn digest_challenges() {
+        let (listener, port) = crate::tests::local();
+        Http11::listen_on(listener, &appliance).unwrap();
+        let uri = format!("http://127.0.0.1:{}/status?verbose", port);
+
+        let response = Http::get(&uri).unwrap();
+        assert!(matches!(response.status, Status::Unauthorized));
+        let challenge = DigestChallenge::parse(response.message.headers.get("www-authenticate").unwrap()).unwrap();
+        assert_eq!(challenge.algorithm, Algorithm::Sha256);
+
+        // the first request is challenged, the next one reuses the nonce with the next count
+        let mut client = Http::new();
+        client.credentials.insert("127.0.0.1".to_string(), Credentials::digest("admin", "secret"));
+        assert_eq!(client.fetch(&uri).unwrap().message.payload.raw(), b"admin");
+        assert_eq!(client.fetch(&uri).unwrap().message.payload.raw(), b"admin");
+        assert_eq!(client.digests.get("127.0.0.1").unwrap().1, 2);
+
+        // a replayed count is refused
+        let (challenge, _) = client.digests.get("127.0.0.1").unwrap().clone();
+        let mut request = Request::new();
+        request.message.headers.add(challenge.answer("admin", "secret", Method::GET, "/status?verbose", 2).header());
+        assert!(matches!(Http::new().send(&uri, &request).unwrap().status, Status::Unauthorized));
+
+        let mut client = Http::new();
+        client.credentials.insert("127.0.0.1".to_string(), Credentials::digest("admin", "wrong"));
+        assert!(matches!(client.fetch(&uri).unwrap().status, Status::Unauthorized));
+    }
+}
@@ -32,72 +32,12 @@ use regex::Regex;
 // the important thing to take out is this: in our scenario mio would be great to write a single-thread listen scheduler which allows for 
 // handling connections while others block (this idea might even get used)
 
-#[derive(Clone, Copy)]
-struct Range {
-    offset: usize,
-    length: usize
-}
-
-impl Range {
-    pub fn new(offset: usize, length: usize) -> Self {
-        Self { offset, length }
-    }
-}
-
-impl std::ops::Index<Range> for String {
-    type Output = str;
-
-    fn index(&self, index: Range) -> &str {
-        &self[index.offset..(index.offset + index.length)]
-    }
-}
-
-struct TestHeader {
-    name: Range,
-    value: Range
-}
-
-struct Test {
-    pub text: String,
-    references: HashMap<String, TestHeader>
-}
-
-impl Test {
-    pub fn new(text: String) -> Self {
-        Self { text, references: HashMap::new() }
-    }
-
-    pub fn test(&mut self) {
-        for line in self.text.split('\n') {
-            let mut pair = line.split(':');
-            let name = pair.next().unwrap();
-            let name_range = Range::new(name.as_ptr() as usize - self.text.as_ptr() as usize, name.len());
-            let value = pair.next().unwrap();
-            let value_range = Range::new(value.as_ptr() as usize - self.text.as_ptr() as usize, value.len());
-            self.references.insert(line.to_lowercase(), TestHeader { name: name_range, value: value_range });
-        }
-    }
-
-    pub fn get(&self) -> Vec<(&str, &str)> {
-        self.references.values().map(|r| (&self.text[r.name], &self.text[r.value])).collect()
-    }
-}
-
-// sum like dis?
-// if we also added IntoTestEnum trait ...? could work
-// also we could replace the pointer with NonNull or range
-// enum TestEnum<'a> {
-//     Cow(Cow<'a, str>),
-//     Pointer(*const str)
-// }
-
-// --- fix unsafes (static str or range+str)
 // --- maybe replace parse option return with results (more compact)
 
 fn main() {
     // let mut h = Https11::new(Address::new("google.com", Some(443))).unwrap();
-    // h = h.send(&mut Request::new()).unwrap();
-    // println!("{}", h.response.text());
+    // let response = h.send(&mut Request::new()).unwrap();
+    // println!("{}", response.text());
 
     let listener = TcpListener::bind("127.0.0.1:443").unwrap();
     let mut socket = listener.accept().unwrap().0;
@@ -107,7 +47,8 @@ fn main() {
     let mut buffer = [0; 2000];
     stream.read_exact(&mut buffer).unwrap();
     println!("{}", String::from_utf8_lossy(&buffer));
-    // let h = Https11::listen(Address::new("127.0.0.1", Some(443)), &test2);
+    // let config = server_config_pem(&fs::read("cert.pem").unwrap(), &fs::read("key.pem").unwrap()).unwrap();
+    // let h = Https11::listen(Address::new("127.0.0.1", Some(443)), config, &test2);
     // h.unwrap().join().unwrap();
 }
 
@@ -0,0 +1,504 @@
+use std::error::Error;
+use std::fmt::{ self, Display, Formatter };
+use std::fs;
+use std::io::{ self, Write };
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use crate::def::*;
+use crate::message::*;
+use crate::http::*;
+
+// cookies as described in rfc 6265
+
+const NETSCAPE_HEADER: &str = "# Netscape HTTP Cookie File";
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None
+}
+
+impl SameSite {
+    pub fn parse(same_site: &str) -> Option<Self> {
+        match same_site.to_lowercase().as_str() {
+            "strict" => Some(Self::Strict),
+            "lax" => Some(Self::Lax),
+            "none" => Some(Self::None),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None"
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub expires: Option<SystemTime>,
+    // wins over expires, zero removes the cookie
+    pub max_age: Option<Duration>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>
+}
+
+impl Cookie {
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, value: V) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            expires: None,
+            max_age: None,
+            domain: None,
+            path: None,
+            secure: false,
+            http_only: false,
+            same_site: None
+        }
+    }
+
+    // the value of a Set-Cookie header, attributes that don't parse are ignored (rfc 6265 section 5.2)
+    pub fn parse(set_cookie: &str) -> Option<Self> {
+        let mut attributes = set_cookie.split(';');
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() { return None }
+
+        let mut cookie = Cookie::new(name, value.trim());
+        for attribute in attributes {
+            let (name, value) = match attribute.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => (attribute.trim(), "")
+            };
+            match name.to_lowercase().as_str() {
+                "expires" => if let Some(expires) = parse_date(value) {
+                    cookie.expires = Some(expires);
+                },
+                "max-age" => if let Ok(seconds) = value.parse::<i64>() {
+                    cookie.max_age = Some(Duration::from_secs(seconds.max(0) as u64));
+                },
+                "domain" if !value.is_empty() => cookie.domain = Some(value.trim_start_matches('.').to_lowercase()),
+                "path" => cookie.path = Some(value.to_string()).filter(|path| path.starts_with('/')),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => cookie.same_site = SameSite::parse(value),
+                _ => ()
+            }
+        }
+
+        Some(cookie)
+    }
+
+    // when the cookie stops being valid, None for session cookies
+    pub fn expiry(&self) -> Option<SystemTime> {
+        match self.max_age {
+            Some(max_age) => Some(SystemTime::now() + max_age),
+            None => self.expires
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiry().is_some_and(|expiry| expiry <= SystemTime::now())
+    }
+
+    // a Set-Cookie that makes clients drop the cookie, domain and path have to match the one it replaces
+    pub fn removal<N: Into<String>>(name: N) -> Self {
+        let mut cookie = Cookie::new(name, "");
+        cookie.expires = Some(UNIX_EPOCH);
+        cookie.max_age = Some(Duration::from_secs(0));
+        cookie
+    }
+
+    // the value of a Set-Cookie header
+    pub fn construct(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Display for Cookie {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", httpdate::fmt_http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+
+        Ok(())
+    }
+}
+
+// the name and value pairs of a Cookie header, values may come in quotes
+pub fn parse_cookies(cookie: &str) -> impl Iterator<Item = (&str, &str)> {
+    cookie.split(';').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        let name = name.trim();
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        if name.is_empty() { None } else { Some((name, value)) }
+    })
+}
+
+impl<'a> Request<'a> {
+    // http/2 clients may split the cookies over several fields
+    pub fn cookies(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.message.headers.get_all("cookie").flat_map(parse_cookies)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies().find(|(n, _)| *n == name).map(|(_, value)| value)
+    }
+}
+
+impl<'a> Response<'a> {
+    // every cookie gets its own Set-Cookie field
+    pub fn set_cookie(&mut self, cookie: &Cookie) {
+        self.message.headers.append(Header::new("Set-Cookie", cookie.construct()));
+    }
+}
+
+// expires dates come in every format servers ever used, the dashed netscape one included
+fn parse_date(date: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(date).or_else(|_| httpdate::parse_http_date(&date.replace('-', " "))).ok()
+}
+
+#[derive(Clone)]
+struct Stored {
+    // domain, path and expires hold the effective values
+    cookie: Cookie,
+    host_only: bool,
+    creation: u64
+}
+
+impl Stored {
+    fn matches(&self, domain: &str, protocol: Protocol, path: &str) -> bool {
+        let cookie_domain = self.cookie.domain.as_deref().unwrap_or_default();
+        let domain_matches = if self.host_only { domain == cookie_domain } else { domain_match(domain, cookie_domain) };
+        domain_matches
+            && path_match(path, self.cookie.path.as_deref().unwrap_or("/"))
+            && (!self.cookie.secure || matches!(protocol, Protocol::Https))
+            && !self.cookie.is_expired()
+    }
+}
+
+// the cookies a client received, sent back to the servers they belong to
+#[derive(Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<Stored>,
+    created: u64
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // stores the Set-Cookie headers of the response to a request for the location
+    pub fn receive(&mut self, address: &Address, protocol: Protocol, location: &str, headers: &Headers) {
+        for set_cookie in headers.get_all("set-cookie") {
+            if let Some(cookie) = Cookie::parse(set_cookie) {
+                self.store(address, protocol, location, cookie);
+            }
+        }
+    }
+
+    // cookies for other domains and secure cookies over plain http are ignored (rfc 6265 section 5.3)
+    pub fn store(&mut self, address: &Address, protocol: Protocol, location: &str, mut cookie: Cookie) {
+        let host = address.domain().to_lowercase();
+        let host_only = match &cookie.domain {
+            // without a public suffix list, single labels are only allowed for the host itself
+            Some(domain) if !domain_match(&host, domain) || (!domain.contains('.') && *domain != host) => return,
+            Some(_) => false,
+            None => {
+                cookie.domain = Some(host);
+                true
+            }
+        };
+        if cookie.secure && !matches!(protocol, Protocol::Https) { return }
+        if cookie.path.is_none() {
+            cookie.path = Some(default_path(location).to_string());
+        }
+        cookie.expires = cookie.expiry();
+        cookie.max_age = None;
+
+        // a cookie with the same name, domain and path is replaced but keeps its place in line
+        let existing = self.cookies.iter().position(|stored| {
+            stored.cookie.name == cookie.name && stored.cookie.domain == cookie.domain && stored.cookie.path == cookie.path
+        });
+        let creation = match existing {
+            Some(position) => self.cookies.remove(position).creation,
+            None => {
+                self.created += 1;
+                self.created
+            }
+        };
+        if !cookie.is_expired() {
+            self.cookies.push(Stored { cookie, host_only, creation });
+        }
+    }
+
+    // the Cookie header for a request, longer paths first and older cookies first among those
+    pub fn header(&self, address: &Address, protocol: Protocol, location: &str) -> Option<Header<'static>> {
+        let domain = address.domain().to_lowercase();
+        let path = location.split('?').next().unwrap_or("/");
+        let mut cookies: Vec<_> = self.cookies.iter().filter(|stored| stored.matches(&domain, protocol, path)).collect();
+        if cookies.is_empty() { return None }
+
+        cookies.sort_by_key(|stored| (usize::MAX - stored.cookie.path.as_ref().map_or(0, String::len), stored.creation));
+        let pairs: Vec<_> = cookies.iter().map(|stored| format!("{}={}", stored.cookie.name, stored.cookie.value)).collect();
+        Some(Header::new("Cookie", pairs.join("; ")))
+    }
+
+    pub fn cookies(&self) -> impl Iterator<Item = &Cookie> {
+        self.cookies.iter().map(|stored| &stored.cookie).filter(|cookie| !cookie.is_expired())
+    }
+
+    pub fn clear(&mut self) {
+        self.cookies.clear();
+    }
+
+    // the netscape cookies.txt format curl and browsers use, session cookies get an expiry of 0
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "{}", NETSCAPE_HEADER)?;
+        for stored in self.cookies.iter().filter(|stored| !stored.cookie.is_expired()) {
+            let cookie = &stored.cookie;
+            let expires = cookie.expires.and_then(|expires| expires.duration_since(UNIX_EPOCH).ok()).map_or(0, |expires| expires.as_secs());
+            writeln!(file, "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                if cookie.http_only { HTTP_ONLY_PREFIX } else { "" },
+                if stored.host_only { "" } else { "." },
+                cookie.domain.as_deref().unwrap_or_default(),
+                flag(!stored.host_only),
+                cookie.path.as_deref().unwrap_or("/"),
+                flag(cookie.secure),
+                expires,
+                cookie.name,
+                cookie.value)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let mut jar = CookieJar::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("malformed cookie on line {}", number + 1));
+            let (http_only, line) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+                Some(line) => (true, line),
+                None => (false, line)
+            };
+            if line.trim().is_empty() || line.starts_with('#') { continue }
+
+            let fields: Vec<_> = line.split('\t').collect();
+            if fields.len() != 7 { Err(invalid())? }
+            let expires = fields[4].parse::<u64>().map_err(|_| invalid())?;
+
+            let mut cookie = Cookie::new(fields[5], fields[6]);
+            cookie.domain = Some(fields[0].trim_start_matches('.').to_lowercase());
+            cookie.path = Some(fields[2].to_string());
+            cookie.secure = fields[3] == "TRUE";
+            cookie.http_only = http_only;
+            cookie.expires = if expires == 0 { None } else { Some(UNIX_EPOCH + Duration::from_secs(expires)) };
+            if cookie.is_expired() { continue }
+
+            jar.created += 1;
+            jar.cookies.push(Stored { cookie, host_only: fields[1] != "TRUE", creation: jar.created });
+        }
+
+        Ok(jar)
+    }
+}
+
+fn flag(value: bool) -> &'static str {
+    if value { "TRUE" } else { "FALSE" }
+}
+
+// rfc 6265 section 5.1.3, ip addresses only match themselves
+fn domain_match(domain: &str, cookie_domain: &str) -> bool {
+    domain == cookie_domain
+        || (domain.ends_with(cookie_domain)
+            && domain[..domain.len() - cookie_domain.len()].ends_with('.')
+            && domain.parse::<IpAddr>().is_err())
+}
+
+// rfc 6265 section 5.1.4
+fn default_path(location: &str) -> &str {
+    let path = location.split('?').next().unwrap_or_default();
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(last) => &path[..last]
+    }
+}
+
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path) && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    #[test]
+    fn parse_set_cookie() {
+        let cookie = Cookie::parse("id=a3fWa; Expires=Wed, 21 Oct 2037 07:28:00 GMT; Domain=.Example.com; Path=/docs; Secure; HttpOnly; SameSite=Lax").unwrap();
+        assert_eq!((cookie.name.as_str(), cookie.value.as_str()), ("id", "a3fWa"));
+        assert_eq!(cookie.expires, Some(UNIX_EPOCH + Duration::from_secs(2139722880)));
+        assert_eq!(cookie.domain.as_deref(), Some("example.com"));
+        assert_eq!(cookie.path.as_deref(), Some("/docs"));
+        assert!(cookie.secure && cookie.http_only);
+        assert_eq!(cookie.same_site, Some(SameSite::Lax));
+
+        let cookie = Cookie::parse("old=1; expires=Thu, 01-Jan-1970 00:00:01 GMT; max-age=-5; path=relative").unwrap();
+        assert_eq!(cookie.max_age, Some(Duration::from_secs(0)));
+        assert_eq!(cookie.path, None);
+        assert!(cookie.is_expired());
+        assert!(Cookie::parse("no value").is_none());
+    }
+
+    #[test]
+    fn set_cookie_header() {
+        let mut cookie = Cookie::new("id", "a3fWa");
+        cookie.expires = Some(UNIX_EPOCH + Duration::from_secs(2139722880));
+        cookie.max_age = Some(Duration::from_secs(3600));
+        cookie.domain = Some("example.com".to_string());
+        cookie.path = Some("/".to_string());
+        cookie.secure = true;
+        cookie.http_only = true;
+        cookie.same_site = Some(SameSite::Strict);
+        let set_cookie = cookie.construct();
+        assert_eq!(set_cookie, "id=a3fWa; Expires=Wed, 21 Oct 2037 07:28:00 GMT; Max-Age=3600; Domain=example.com; Path=/; Secure; HttpOnly; SameSite=Strict");
+        assert_eq!(Cookie::parse(&set_cookie).unwrap(), cookie);
+
+        let mut response = Response::new();
+        response.set_cookie(&cookie);
+        response.set_cookie(&Cookie::removal("old"));
+        let construct = String::from_utf8(response.construct()).unwrap();
+        assert!(construct.contains("Set-Cookie: id=a3fWa;"));
+        assert!(construct.contains("Set-Cookie: old=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0\r\n"));
+    }
+
+    #[test]
+    fn request_cookies() {
+        let mut request = Request::new();
+        request.message.headers.append(Header::new("Cookie", "session=42; theme=\"dark\";broken"));
+        request.message.headers.append(Header::new("Cookie", "lang=en"));
+        assert_eq!(request.cookies().collect::<Vec<_>>(), vec![("session", "42"), ("theme", "dark"), ("lang", "en")]);
+        assert_eq!(request.cookie("lang"), Some("en"));
+        assert_eq!(request.cookie("missing"), None);
+    }
+
+    #[test]
+    fn match_requests() {
+        let address = Address::new("www.example.com", None);
+        let mut jar = CookieJar::new();
+        let mut headers = Headers::new();
+        headers.append(Header::new("Set-Cookie", "host=1"));
+        headers.append(Header::new("Set-Cookie", "shared=2; Domain=example.com; Path=/"));
+        headers.append(Header::new("Set-Cookie", "docs=3; Path=/docs"));
+        headers.append(Header::new("Set-Cookie", "secure=4; Secure"));
+        headers.append(Header::new("Set-Cookie", "foreign=5; Domain=example.org"));
+        headers.append(Header::new("Set-Cookie", "tld=6; Domain=com"));
+        jar.receive(&address, Protocol::Https, "/account/login", &headers);
+
+        fn cookie(jar: &CookieJar, domain: &str, protocol: Protocol, location: &str) -> Option<String> {
+            jar.header(&Address::new(domain.to_string(), None), protocol, location).map(|header| header.value.to_string())
+        }
+        assert_eq!(cookie(&jar, "www.example.com", Protocol::Https, "/docs/api").as_deref(), Some("docs=3; shared=2"));
+        assert_eq!(cookie(&jar, "www.example.com", Protocol::Https, "/account").as_deref(), Some("host=1; secure=4; shared=2"));
+        assert_eq!(cookie(&jar, "www.example.com", Protocol::Http, "/account/settings?tab=1").as_deref(), Some("host=1; shared=2"));
+        assert_eq!(cookie(&jar, "api.example.com", Protocol::Http, "/docsearch").as_deref(), Some("shared=2"));
+        assert_eq!(cookie(&jar, "example.org", Protocol::Https, "/"), None);
+
+        // an expired cookie with the same name, domain and path removes the stored one
+        jar.store(&address, Protocol::Https, "/", Cookie::parse("shared=; Domain=example.com; Max-Age=0").unwrap());
+        assert_eq!(cookie(&jar, "api.example.com", Protocol::Http, "/"), None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let address = Address::new("example.com", None);
+        let mut jar = CookieJar::new();
+        jar.store(&address, Protocol::Https, "/", Cookie::parse("session=abc; HttpOnly").unwrap());
+        jar.store(&address, Protocol::Https, "/", Cookie::parse("remember=1; Domain=example.com; Max-Age=3600; Secure").unwrap());
+
+        let path = std::env::temp_dir().join(format!("cookies-{}.txt", std::process::id()));
+        jar.save(&path).unwrap();
+        let loaded = CookieJar::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let cookies: Vec<_> = loaded.cookies().collect();
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies[0].http_only && cookies[0].expires.is_none());
+        assert!(cookies[1].secure && cookies[1].expires.is_some());
+        assert_eq!(loaded.header(&Address::new("sub.example.com", None), Protocol::Https, "/").unwrap().value, "remember=1");
+        assert_eq!(loaded.header(&address, Protocol::Https, "/").unwrap().value, "session=abc; remember=1");
+
+        fs::write(&path, "# Netscape HTTP Cookie File\nexample.com\tFALSE\t/\n").unwrap();
+        let error = CookieJar::load(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        let error = error.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"));
+    }
+
+    static PORT: AtomicUsize = AtomicUsize::new(0);
+
+    fn login(request: Request) -> Option<Response> {
+        let mut response = Response::new();
+        match request.target.location.as_ref() {
+            "/login" => {
+                response.status = Status::MovedPermanently;
+                response.message.headers.add(Header::new("Location", format!("http://127.0.0.1:{}/private", PORT.load(Ordering::SeqCst))));
+                response.message.headers.append(Header::new("Set-Cookie", "session=42; Path=/; HttpOnly"));
+                response.message.headers.append(Header::new("Set-Cookie", "theme=dark; Path=/settings"));
+            }
+            _ => {
+                let cookie = request.message.headers.get("cookie").unwrap_or_default().to_string();
+                response.message.payload = Payload::Identity(cookie.into_bytes().into());
+            }
+        }
+        Some(response)
+    }
+
+    #[test]
+    fn cookies_across_redirects() {
+        let (listener, port) = crate::tests::local();
+        PORT.store(port, Ordering::SeqCst);
+        Http11::listen_on(listener, &login).unwrap();
+
+        let mut client = Http::new();
+        let response = client.fetch(&format!("http://127.0.0.1:{}/login", port)).unwrap();
+        assert_eq!(response.message.payload.raw(), b"session=42");
+        assert_eq!(client.cookies.cookies().count(), 2);
+    }
+}
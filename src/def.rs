@@ -1,24 +1,26 @@
 use std::error::Error;
 use std::borrow::Cow;
-use std::collections::VecDeque;
 use flate2::{
-    Compression, read::{GzDecoder, GzEncoder, DeflateDecoder, DeflateEncoder}
+    Compression, write, read::{GzDecoder, DeflateDecoder}
 };
 use std::fmt::{self, Debug, Display, Formatter};
 use std::io::{self, ErrorKind, Read, Write};
-use lazy_static::lazy_static;
 
 #[derive(Clone, Copy, Debug)]
 pub enum Method {
     GET,
+    HEAD,
     POST,
+    CONNECT,
 }
 
 impl Method {
     pub fn parse(method: &str) -> Option<Self> {
         match method.to_uppercase().as_str() {
             "GET" => Some(Method::GET),
+            "HEAD" => Some(Method::HEAD),
             "POST" => Some(Method::POST),
+            "CONNECT" => Some(Method::CONNECT),
             _ => None,
         }
     }
@@ -28,7 +30,9 @@ impl Display for Method {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Method::GET => write!(f, "GET"),
+            Method::HEAD => write!(f, "HEAD"),
             Method::POST => write!(f, "POST"),
+            Method::CONNECT => write!(f, "CONNECT"),
         }
     }
 }
@@ -49,6 +53,24 @@ impl Version {
             _ => None,
         }
     }
+
+    // protocol ids used in tls alpn (rfc 7301)
+    pub fn alpn(&self) -> &'static [u8] {
+        match self {
+            Version::V1 => b"http/1.0",
+            Version::V11 => b"http/1.1",
+            Version::V2 => b"h2",
+        }
+    }
+
+    pub fn from_alpn(protocol: &[u8]) -> Option<Self> {
+        match protocol {
+            b"http/1.0" => Some(Version::V1),
+            b"http/1.1" => Some(Version::V11),
+            b"h2" => Some(Version::V2),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Version {
@@ -63,17 +85,33 @@ impl Display for Version {
 
 #[derive(Clone, Copy, Debug)]
 pub enum Status {
+    SwitchingProtocols,
     Ok,
+    NoContent,
     NotFound,
-    MovedPermanently
+    MovedPermanently,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout
 }
 
 impl Status {
     pub fn parse(status: &str) -> Option<Self> {
         match status {
+            "101" => Some(Status::SwitchingProtocols),
             "200" => Some(Status::Ok),
+            "204" => Some(Status::NoContent),
             "404" => Some(Status::NotFound),
             "301" => Some(Status::MovedPermanently),
+            "304" => Some(Status::NotModified),
+            "400" => Some(Status::BadRequest),
+            "401" => Some(Status::Unauthorized),
+            "502" => Some(Status::BadGateway),
+            "503" => Some(Status::ServiceUnavailable),
+            "504" => Some(Status::GatewayTimeout),
             _ => None,
         }
     }
@@ -87,9 +125,33 @@ impl Status {
 
     pub fn message(&self) -> &str {
         match self {
+            Status::SwitchingProtocols => "Switching Protocols",
             Status::Ok => "OK",
+            Status::NoContent => "No Content",
             Status::NotFound => "Not Found",
-            Status::MovedPermanently => "Moved Permanently"
+            Status::MovedPermanently => "Moved Permanently",
+            Status::NotModified => "Not Modified",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::BadGateway => "Bad Gateway",
+            Status::ServiceUnavailable => "Service Unavailable",
+            Status::GatewayTimeout => "Gateway Timeout"
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::SwitchingProtocols => 101,
+            Status::Ok => 200,
+            Status::NoContent => 204,
+            Status::NotFound => 404,
+            Status::MovedPermanently => 301,
+            Status::NotModified => 304,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::BadGateway => 502,
+            Status::ServiceUnavailable => 503,
+            Status::GatewayTimeout => 504
         }
     }
 
@@ -100,49 +162,81 @@ impl Status {
 
 impl Display for Status {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self {
-            Status::Ok => write!(f, "200"),
-            Status::NotFound => write!(f, "404"),
-            Status::MovedPermanently => write!(f, "301"),
-        }
+        write!(f, "{}", self.code())
     }
 }
 
-const ENCODING_GZIP: &'static str = "gzip";
-const ENCODING_DEFLATE: &'static str = "deflate";
-const ENCODING_BROTLI: &'static str = "br";
+const ENCODING_GZIP: &str = "gzip";
+const ENCODING_DEFLATE: &str = "deflate";
+const ENCODING_BROTLI: &str = "br";
+const ENCODING_ZSTD: &str = "zstd";
+// zstd picks its default level (3) for 0
+const ZSTD_LEVEL: i32 = 0;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Encoding {
     GZip,
     Deflate,
-    Brotli
+    Brotli,
+    Zstd
 }
 
 impl Encoding {
-    fn decode(&self, encoded: &[u8], decoded: &mut Vec<u8>) -> io::Result<usize> {
-        match self {
-            Self::GZip => GzDecoder::new(encoded).read_to_end(decoded),
-            Self::Deflate => DeflateDecoder::new(encoded).read_to_end(decoded),
-            Self::Brotli => brotli::Decompressor::new(encoded, 4096).read_to_end(decoded),
+    // zstd allocates its context up front, which can fail
+    fn decoder<R: Read>(&self, reader: Decoder<R>) -> io::Result<Decoder<R>> {
+        Ok(match self {
+            Self::GZip => Decoder::GZip(Box::new(GzDecoder::new(reader))),
+            Self::Deflate => Decoder::Deflate(Box::new(DeflateDecoder::new(reader))),
+            Self::Brotli => Decoder::Brotli(Box::new(brotli::Decompressor::new(reader, 4096))),
+            Self::Zstd => Decoder::Zstd(Box::new(zstd::stream::read::Decoder::new(reader)?))
+        })
+    }
+
+    fn encoder<W: Write>(&self, writer: Encoder<W>) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Self::GZip => Encoder::GZip(Box::new(write::GzEncoder::new(writer, Compression::default()))),
+            Self::Deflate => Encoder::Deflate(Box::new(write::DeflateEncoder::new(writer, Compression::default()))),
+            Self::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(writer, 4096, 3, 20))),
+            Self::Zstd => Encoder::Zstd(Box::new(zstd::stream::write::Encoder::new(writer, ZSTD_LEVEL)?))
+        })
+    }
+
+    // the supported coding the client ranks highest in Accept-Encoding (rfc 7231 section 5.3.4)
+    // ties go to the order of supported, None when the payload should go out as is
+    pub fn negotiate(accept_encoding: &str, supported: &[Encoding]) -> Option<Encoding> {
+        let preferences: Vec<(String, f32)> = accept_encoding.split(',').map(|item| {
+            let mut parameters = item.split(';');
+            let coding = parameters.next().unwrap_or("").trim().to_lowercase();
+            let quality = parameters.map(|p| p.trim().to_lowercase())
+                .find_map(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+                .unwrap_or(1.0);
+            (coding, quality)
+        }).collect();
+
+        // codings that aren't listed are only acceptable through *
+        let quality = |coding: &str| preferences.iter().find(|(c, _)| c == coding)
+            .or_else(|| preferences.iter().find(|(c, _)| c == "*"))
+            .map_or(0.0, |(_, q)| *q);
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in supported {
+            let q = quality(encoding.value());
+            if q > 0.0 && best.is_none_or(|(_, b)| q > b) {
+                best = Some((*encoding, q));
+            }
         }
-    }
 
-    fn encode(&self, payload: &[u8], encoded: &mut Vec<u8>) -> io::Result<usize> {
-        match self {
-            Self::GZip => GzEncoder::new(payload, Compression::default()).read_to_end(encoded),
-            Self::Deflate => DeflateEncoder::new(payload, Compression::default()).read_to_end(encoded),
-            Self::Brotli => brotli::CompressorReader::new(payload, 4096, 3, 20).read_to_end(encoded)
-        }
+        best.map(|(encoding, _)| encoding)
     }
 }
 
 impl Parsable for Encoding {
     fn parse(value: &str) -> Option<Self> {
-        match value.to_lowercase().as_str() {
+        match value.trim().to_lowercase().as_str() {
             ENCODING_GZIP => Some(Self::GZip),
             ENCODING_DEFLATE => Some(Self::Deflate),
             ENCODING_BROTLI => Some(Self::Brotli),
+            ENCODING_ZSTD => Some(Self::Zstd),
             _ => None,
         }
     }
@@ -154,7 +248,8 @@ impl ToHeader for Encoding {
         match self {
             Self::GZip => ENCODING_GZIP,
             Self::Deflate => ENCODING_DEFLATE,
-            Self::Brotli => ENCODING_BROTLI
+            Self::Brotli => ENCODING_BROTLI,
+            Self::Zstd => ENCODING_ZSTD
         }
     }
 }
@@ -162,8 +257,10 @@ impl ToHeader for Encoding {
 #[derive(Clone, Copy, Debug)]
 pub enum ContentType {
     HTML,
+    EventStream,
 }
 
+#[derive(Clone, Debug)]
 pub struct Encodings(Vec<Encoding>);
 
 impl Encodings {
@@ -171,33 +268,131 @@ impl Encodings {
         Self(Encoding::parse_many(encodings))
     }
 
+    // in the order they were applied
+    pub fn list(&self) -> &[Encoding] {
+        &self.0
+    }
+
+    // every layer is decoded in the same pass, only the result is held in memory
     pub fn decode(&self, encoded: &[u8]) -> io::Result<Vec<u8>> {
-        let mut buffer = encoded.to_vec();
-        let mut decoded;
+        let mut decoded = Vec::new();
+        self.decoder(encoded)?.read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
 
-        for encoding in self.0.iter().rev() {
-            decoded = Vec::new();
-            encoding.decode(&buffer, &mut decoded)?;
-            buffer = decoded;
+    // wraps the reader so the layers are decoded lazily as it is read
+    pub fn decoder<R: Read>(&self, reader: R) -> io::Result<Decoder<R>> {
+        self.0.iter().rev().try_fold(Decoder::Identity(reader), |reader, encoding| encoding.decoder(reader))
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = self.encoder(Vec::new())?;
+        encoder.write_all(payload)?;
+        encoder.finish()
+    }
+
+    // wraps the writer so everything written to it is encoded, finish the encoder to end the payload
+    pub fn encoder<W: Write>(&self, writer: W) -> io::Result<Encoder<W>> {
+        self.0.iter().rev().try_fold(Encoder::Identity(writer), |writer, encoding| encoding.encoder(writer))
+    }
+}
+
+impl From<Vec<Encoding>> for Encodings {
+    fn from(encodings: Vec<Encoding>) -> Self {
+        Self(encodings)
+    }
+}
+
+// a chain of decoders over the encoded reader, the innermost layer is always identity
+pub enum Decoder<R: Read> {
+    Identity(R),
+    GZip(Box<GzDecoder<Decoder<R>>>),
+    Deflate(Box<DeflateDecoder<Decoder<R>>>),
+    Brotli(Box<brotli::Decompressor<Decoder<R>>>),
+    Zstd(Box<zstd::stream::read::Decoder<'static, io::BufReader<Decoder<R>>>>)
+}
+
+impl<R: Read> Decoder<R> {
+    // the encoded reader, decoders may stop short of its end (e.g. after the gzip trailer)
+    pub fn get_mut(&mut self) -> &mut R {
+        match self {
+            Self::Identity(reader) => reader,
+            Self::GZip(decoder) => decoder.get_mut().get_mut(),
+            Self::Deflate(decoder) => decoder.get_mut().get_mut(),
+            Self::Brotli(decoder) => decoder.get_mut().get_mut(),
+            Self::Zstd(decoder) => decoder.get_mut().get_mut().get_mut()
         }
+    }
+}
 
-        Ok(buffer)
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Identity(reader) => reader.read(buf),
+            Self::GZip(decoder) => decoder.read(buf),
+            Self::Deflate(decoder) => decoder.read(buf),
+            Self::Brotli(decoder) => decoder.read(buf),
+            Self::Zstd(decoder) => decoder.read(buf)
+        }
     }
+}
 
-    pub fn encode(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
-        let mut buffer = payload.to_vec();
-        let mut encoded;
+// a chain of encoders over the writer, the first coding applied is the outermost layer
+pub enum Encoder<W: Write> {
+    Identity(W),
+    GZip(Box<write::GzEncoder<Encoder<W>>>),
+    Deflate(Box<write::DeflateEncoder<Encoder<W>>>),
+    Brotli(Box<brotli::CompressorWriter<Encoder<W>>>),
+    Zstd(Box<zstd::stream::write::Encoder<'static, Encoder<W>>>)
+}
 
-        for encoding in &self.0 {
-            encoded = Vec::new();
-            encoding.encode(&buffer, &mut encoded)?;
-            buffer = encoded;
+impl<W: Write> Encoder<W> {
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Identity(writer) => writer,
+            Self::GZip(encoder) => encoder.get_mut().get_mut(),
+            Self::Deflate(encoder) => encoder.get_mut().get_mut(),
+            Self::Brotli(encoder) => encoder.get_mut().get_mut(),
+            Self::Zstd(encoder) => encoder.get_mut().get_mut()
         }
+    }
 
-        Ok(buffer)
+    // writes the trailers of every layer, outermost first
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Identity(writer) => Ok(writer),
+            Self::GZip(encoder) => encoder.finish()?.finish(),
+            Self::Deflate(encoder) => encoder.finish()?.finish(),
+            // brotli only flushes what is left when it gives the writer back
+            Self::Brotli(encoder) => encoder.into_inner().finish(),
+            Self::Zstd(encoder) => encoder.finish()?.finish()
+        }
     }
 }
 
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Identity(writer) => writer.write(buf),
+            Self::GZip(encoder) => encoder.write(buf),
+            Self::Deflate(encoder) => encoder.write(buf),
+            Self::Brotli(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Identity(writer) => writer.flush(),
+            Self::GZip(encoder) => encoder.flush(),
+            Self::Deflate(encoder) => encoder.flush(),
+            Self::Brotli(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush()
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Header<'a> {
     pub name: Cow<'a, str>,
     pub value: Cow<'a, str>
@@ -215,7 +410,7 @@ impl<'a> Header<'a> {
     pub fn from_many<T: ToHeader>(to_headers: &[T]) -> Self {
         Self { 
             name: T::normalized().into(), 
-            value: to_headers.into_iter().map(|x| x.value()).collect::<Vec<_>>().join(T::delimiter()).into() 
+            value: to_headers.iter().map(|x| x.value()).collect::<Vec<_>>().join(T::delimiter()).into() 
         }
     }
 
@@ -228,17 +423,22 @@ impl<'a> Header<'a> {
     }
 
     pub fn construct(&self) -> String {
-        format!("{}: {}", self.name, self.value)
+        self.to_string()
+    }
+
+    pub fn into_owned(self) -> Header<'static> {
+        Header { name: Cow::Owned(self.name.into_owned()), value: Cow::Owned(self.value.into_owned()) }
     }
 }
 
 impl<'a> Display for Header<'a> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{}", self.construct())
+        write!(f, "{}: {}", self.name, self.value)
     }
 }
 
-const CONTENT_TYPE_HTML: &'static str = "text/html";
+const CONTENT_TYPE_HTML: &str = "text/html";
+const CONTENT_TYPE_EVENT_STREAM: &str = "text/event-stream";
 
 // ehh the name
 pub trait Parsable where Self: Sized {
@@ -255,19 +455,11 @@ pub trait ToHeader where Self: Parsable {
     }
 }
 
-impl ContentType {  
-    fn from_extension(extension: &str) -> Option<Self> {
-        match extension.to_lowercase().as_str() {
-            "html" => Some(ContentType::HTML),
-            _ => None,
-        }
-    }
-}
-
 impl Parsable for ContentType {
     fn parse(value: &str) -> Option<Self> {
         match value.to_lowercase().as_str() {
             CONTENT_TYPE_HTML => Some(ContentType::HTML),
+            CONTENT_TYPE_EVENT_STREAM => Some(ContentType::EventStream),
             _ => None,
         }
     }
@@ -277,7 +469,8 @@ impl ToHeader for ContentType {
     fn normalized() -> &'static str { "content-type" }
     fn value(&self) -> &'static str {
         match self {
-            ContentType::HTML => CONTENT_TYPE_HTML
+            ContentType::HTML => CONTENT_TYPE_HTML,
+            ContentType::EventStream => CONTENT_TYPE_EVENT_STREAM
         }
     }
 }
@@ -287,8 +480,8 @@ pub enum TransferEncoding {
     Indentity
 }
 
-const TRANSFER_ENCODING_CHUNKED: &'static str = "chunked";
-const TRANSFER_ENCODING_IDENTITY: &'static str = "identity";
+const TRANSFER_ENCODING_CHUNKED: &str = "chunked";
+const TRANSFER_ENCODING_IDENTITY: &str = "identity";
 
 impl Parsable for TransferEncoding {
     fn parse(value: &str) -> Option<Self> {
@@ -311,12 +504,13 @@ impl ToHeader for TransferEncoding {
     fn is_multi() -> bool { false }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum Protocol {
     Http, Https
 }
 
-const PROTOCOL_HTTP: &'static str = "http";
-const PROTOCOL_HTTPS: &'static str = "https";
+const PROTOCOL_HTTP: &str = "http";
+const PROTOCOL_HTTPS: &str = "https";
 
 impl Parsable for Protocol {
     fn parse(protocol: &str) -> Option<Self> {
@@ -337,8 +531,8 @@ impl Protocol {
     }
 }
 
-const CONNECTION_CLOSE: &'static str = "close";
-const CONNECTION_KEEP_ALIVE: &'static str = "keep-alive";
+const CONNECTION_CLOSE: &str = "close";
+const CONNECTION_KEEP_ALIVE: &str = "keep-alive";
 
 pub enum Connection {
     Close, KeepAlive
@@ -0,0 +1,1467 @@
+use std::borrow::Cow;
+use std::collections::{ HashMap, VecDeque };
+use std::error::Error;
+use std::fmt::{ self, Display, Formatter };
+use std::io::{ self, Read, Write, BufReader };
+use std::net::{ Shutdown, TcpStream, TcpListener };
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::mpsc::{ self, SyncSender, Receiver };
+use std::thread;
+use crate::def::*;
+use crate::message::*;
+use crate::http::*;
+use crate::hpack;
+use crate::ws::has_token;
+
+// http/2 (rfc 7540) on top of any blocking stream, streams are multiplexed by
+// interleaving their frames on the one connection instead of using threads
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const ALPN_H2: &[u8] = b"h2";
+pub const UPGRADE_H2C: &str = "h2c";
+
+const FRAME_HEADER_SIZE: usize = 9;
+const DEFAULT_WINDOW_SIZE: u32 = 65535;
+const DEFAULT_FRAME_SIZE: usize = 16384;
+const MAX_FRAME_SIZE: usize = (1 << 24) - 1;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+// what we let the peer send ahead of our window updates, per stream and for the connection
+const WINDOW_SIZE: u32 = 1 << 20;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+// headers that only make sense for a single http/1.1 hop
+const CONNECTION_HEADERS: [&str; 6] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "host"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameKind {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    Unknown(u8)
+}
+
+impl FrameKind {
+    pub fn parse(kind: u8) -> Self {
+        match kind {
+            0x0 => Self::Data,
+            0x1 => Self::Headers,
+            0x2 => Self::Priority,
+            0x3 => Self::RstStream,
+            0x4 => Self::Settings,
+            0x5 => Self::PushPromise,
+            0x6 => Self::Ping,
+            0x7 => Self::GoAway,
+            0x8 => Self::WindowUpdate,
+            0x9 => Self::Continuation,
+            kind => Self::Unknown(kind)
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Data => 0x0,
+            Self::Headers => 0x1,
+            Self::Priority => 0x2,
+            Self::RstStream => 0x3,
+            Self::Settings => 0x4,
+            Self::PushPromise => 0x5,
+            Self::Ping => 0x6,
+            Self::GoAway => 0x7,
+            Self::WindowUpdate => 0x8,
+            Self::Continuation => 0x9,
+            Self::Unknown(kind) => *kind
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    NoError,
+    ProtocolError,
+    InternalError,
+    FlowControlError,
+    SettingsTimeout,
+    StreamClosed,
+    FrameSizeError,
+    RefusedStream,
+    Cancel,
+    CompressionError,
+    ConnectError,
+    EnhanceYourCalm,
+    InadequateSecurity,
+    Http11Required
+}
+
+impl ErrorCode {
+    pub fn parse(code: u32) -> Self {
+        match code {
+            0x0 => Self::NoError,
+            0x1 => Self::ProtocolError,
+            0x3 => Self::FlowControlError,
+            0x4 => Self::SettingsTimeout,
+            0x5 => Self::StreamClosed,
+            0x6 => Self::FrameSizeError,
+            0x7 => Self::RefusedStream,
+            0x8 => Self::Cancel,
+            0x9 => Self::CompressionError,
+            0xa => Self::ConnectError,
+            0xb => Self::EnhanceYourCalm,
+            0xc => Self::InadequateSecurity,
+            0xd => Self::Http11Required,
+            // unknown codes must be treated as internal errors
+            _ => Self::InternalError
+        }
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::NoError => 0x0,
+            Self::ProtocolError => 0x1,
+            Self::InternalError => 0x2,
+            Self::FlowControlError => 0x3,
+            Self::SettingsTimeout => 0x4,
+            Self::StreamClosed => 0x5,
+            Self::FrameSizeError => 0x6,
+            Self::RefusedStream => 0x7,
+            Self::Cancel => 0x8,
+            Self::CompressionError => 0x9,
+            Self::ConnectError => 0xa,
+            Self::EnhanceYourCalm => 0xb,
+            Self::InadequateSecurity => 0xc,
+            Self::Http11Required => 0xd
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for ErrorCode { }
+
+pub struct Frame {
+    pub kind: FrameKind,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>
+}
+
+impl Frame {
+    pub fn new(kind: FrameKind, flags: u8, stream: u32, payload: Vec<u8>) -> Self {
+        Self { kind, flags, stream, payload }
+    }
+
+    pub fn read<R: Read>(reader: &mut R, max_size: usize) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if length > max_size { Err(ErrorCode::FrameSizeError)? }
+
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload)?;
+        Ok(Self {
+            kind: FrameKind::parse(header[3]),
+            flags: header[4],
+            stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+            payload
+        })
+    }
+
+    pub fn construct(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + self.payload.len());
+        frame.extend(&(self.payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(self.kind.code());
+        frame.push(self.flags);
+        frame.extend(&self.stream.to_be_bytes());
+        frame.extend(&self.payload);
+        frame
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.construct())
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // the payload of data and headers frames without padding and priority
+    fn content(&self) -> Result<&[u8], ErrorCode> {
+        let mut start = 0;
+        let mut end = self.payload.len();
+        if self.has(FLAG_PADDED) {
+            let padding = *self.payload.first().ok_or(ErrorCode::FrameSizeError)? as usize;
+            start += 1;
+            end = end.checked_sub(padding).ok_or(ErrorCode::ProtocolError)?;
+        }
+        if self.kind == FrameKind::Headers && self.has(FLAG_PRIORITY) {
+            start += 5;
+        }
+
+        self.payload.get(start..end).ok_or(ErrorCode::FrameSizeError)
+    }
+
+    fn u32_at(&self, index: usize) -> Result<u32, ErrorCode> {
+        let bytes = self.payload.get(index..index + 4).ok_or(ErrorCode::FrameSizeError)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub header_table_size: usize,
+    pub enable_push: bool,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_window_size: u32,
+    pub max_frame_size: usize,
+    pub max_header_list_size: Option<usize>
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            header_table_size: 4096,
+            enable_push: true,
+            max_concurrent_streams: None,
+            initial_window_size: DEFAULT_WINDOW_SIZE,
+            max_frame_size: DEFAULT_FRAME_SIZE,
+            max_header_list_size: None
+        }
+    }
+}
+
+impl Settings {
+    // what we announce, no pushes and larger stream windows
+    pub(crate) fn local() -> Self {
+        Settings { enable_push: false, initial_window_size: WINDOW_SIZE, ..Settings::default() }
+    }
+
+    // applies the values of a settings frame on top of the current ones
+    pub fn apply(&mut self, payload: &[u8]) -> Result<(), ErrorCode> {
+        if !payload.len().is_multiple_of(6) { Err(ErrorCode::FrameSizeError)? }
+
+        for setting in payload.chunks(6) {
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                0x1 => self.header_table_size = value as usize,
+                0x2 if value > 1 => Err(ErrorCode::ProtocolError)?,
+                0x2 => self.enable_push = value == 1,
+                0x3 => self.max_concurrent_streams = Some(value),
+                0x4 if value as i64 > MAX_WINDOW_SIZE => Err(ErrorCode::FlowControlError)?,
+                0x4 => self.initial_window_size = value,
+                0x5 if (value as usize) < DEFAULT_FRAME_SIZE || value as usize > MAX_FRAME_SIZE => Err(ErrorCode::ProtocolError)?,
+                0x5 => self.max_frame_size = value as usize,
+                0x6 => self.max_header_list_size = Some(value as usize),
+                // unknown settings must be ignored
+                _ => ()
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn construct(&self) -> Vec<u8> {
+        let mut settings = vec![
+            (0x1, self.header_table_size as u32),
+            (0x2, self.enable_push as u32),
+            (0x4, self.initial_window_size),
+            (0x5, self.max_frame_size as u32)
+        ];
+        if let Some(streams) = self.max_concurrent_streams { settings.push((0x3, streams)); }
+        if let Some(size) = self.max_header_list_size { settings.push((0x6, size as u32)); }
+
+        let mut payload = Vec::with_capacity(settings.len() * 6);
+        for (id, value) in settings {
+            payload.extend(&(id as u16).to_be_bytes());
+            payload.extend(&value.to_be_bytes());
+        }
+
+        payload
+    }
+}
+
+// the parts both ends share: frame io, header compression, settings and connection flow control
+pub(crate) struct Connection<S: Read + Write> {
+    pub(crate) stream: S,
+    encoder: hpack::Encoder,
+    decoder: hpack::Decoder,
+    pub(crate) local: Settings,
+    pub(crate) remote: Settings,
+    // how much data we may still send on the connection
+    send_window: i64,
+    incoming: Vec<u8>,
+    // a header block still waiting for continuations
+    headers: Option<Frame>
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self {
+            stream,
+            encoder: hpack::Encoder::new(),
+            decoder: hpack::Decoder::new(),
+            local: Settings::local(),
+            remote: Settings::default(),
+            send_window: DEFAULT_WINDOW_SIZE as i64,
+            incoming: Vec::new(),
+            headers: None
+        }
+    }
+
+    // our settings and the larger connection window, sent right after the preface
+    pub(crate) fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write(Frame::new(FrameKind::Settings, 0, 0, self.local.construct()))?;
+        self.write(Frame::new(FrameKind::WindowUpdate, 0, 0, (WINDOW_SIZE - DEFAULT_WINDOW_SIZE).to_be_bytes().to_vec()))
+    }
+
+    pub(crate) fn write(&mut self, frame: Frame) -> Result<(), Box<dyn Error>> {
+        frame.write_to(&mut self.stream)?;
+        Ok(())
+    }
+
+    pub(crate) fn reset(&mut self, stream: u32, error: ErrorCode) -> Result<(), Box<dyn Error>> {
+        self.write(Frame::new(FrameKind::RstStream, 0, stream, error.code().to_be_bytes().to_vec()))
+    }
+
+    pub(crate) fn go_away(&mut self, last_stream: u32, error: ErrorCode) -> Result<(), Box<dyn Error>> {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend(&error.code().to_be_bytes());
+        self.write(Frame::new(FrameKind::GoAway, 0, 0, payload))
+    }
+
+    pub(crate) fn write_headers(&mut self, stream: u32, headers: &[(String, String)], end_stream: bool) -> Result<(), Box<dyn Error>> {
+        let mut block = Vec::new();
+        self.encoder.encode(headers.iter().map(|(n, v)| (n.as_str(), v.as_str())), &mut block);
+
+        let mut fragments = block.chunks(self.remote.max_frame_size).peekable();
+        let mut kind = FrameKind::Headers;
+        let first_flags = if end_stream { FLAG_END_STREAM } else { 0 };
+        loop {
+            let fragment = fragments.next().unwrap_or(&[]);
+            let last = fragments.peek().is_none();
+            let mut flags = if kind == FrameKind::Headers { first_flags } else { 0 };
+            if last { flags |= FLAG_END_HEADERS; }
+            self.write(Frame::new(kind, flags, stream, fragment.to_vec()))?;
+            if last { return Ok(()) }
+            kind = FrameKind::Continuation;
+        }
+    }
+
+    // how much of the payload fits in one data frame right now, given the stream window
+    pub(crate) fn sendable(&self, stream_window: i64, remaining: usize) -> usize {
+        self.send_window.min(stream_window).max(0).min(remaining as i64).min(self.remote.max_frame_size as i64) as usize
+    }
+
+    pub(crate) fn write_data(&mut self, stream: u32, data: &[u8], end_stream: bool) -> Result<(), Box<dyn Error>> {
+        self.send_window -= data.len() as i64;
+        self.write(Frame::new(FrameKind::Data, if end_stream { FLAG_END_STREAM } else { 0 }, stream, data.to_vec()))
+    }
+
+    // reads off the stream until a frame the caller has to deal with, connection level frames are handled here
+    // headers come back with their continuations merged, data and headers without padding
+    pub(crate) fn next(&mut self) -> Result<Frame, Box<dyn Error>> {
+        loop {
+            let frame = self.read_frame()?;
+            if let Some(frame) = self.receive(frame)? {
+                return Ok(frame)
+            }
+        }
+    }
+
+    // like next, but only out of what the reader thread fed in already (see Socket)
+    pub(crate) fn buffered(&mut self) -> Result<Option<Frame>, Box<dyn Error>> {
+        while let Some(frame) = self.take_frame()? {
+            if let Some(frame) = self.receive(frame)? {
+                return Ok(Some(frame))
+            }
+        }
+
+        Ok(None)
+    }
+
+    // partial frames are kept around until the rest of them is read
+    fn read_frame(&mut self) -> Result<Frame, Box<dyn Error>> {
+        loop {
+            if let Some(frame) = self.take_frame()? { return Ok(frame) }
+            self.fill()?;
+        }
+    }
+
+    fn take_frame(&mut self) -> Result<Option<Frame>, Box<dyn Error>> {
+        if self.incoming.len() < FRAME_HEADER_SIZE { return Ok(None) }
+        let length = u32::from_be_bytes([0, self.incoming[0], self.incoming[1], self.incoming[2]]) as usize;
+        if self.incoming.len() < FRAME_HEADER_SIZE + length { return Ok(None) }
+        let frame = Frame::read(&mut &self.incoming[..], self.local.max_frame_size)?;
+        self.incoming.drain(..FRAME_HEADER_SIZE + length);
+        Ok(Some(frame))
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let mut buffer = [0; DEFAULT_FRAME_SIZE];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                Ok(size) => {
+                    self.incoming.extend(&buffer[..size]);
+                    return Ok(())
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e)?
+            }
+        }
+    }
+
+    // whether the client preface came yet, it comes before any frame on a server connection
+    pub(crate) fn preface(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.incoming.len() < PREFACE.len() { return Ok(false) }
+        if &self.incoming[..PREFACE.len()] != PREFACE { Err(ErrorCode::ProtocolError)? }
+        self.incoming.drain(..PREFACE.len());
+        Ok(true)
+    }
+
+    pub(crate) fn apply(&mut self, settings: &[u8]) -> Result<(), ErrorCode> {
+        self.remote.apply(settings)?;
+        self.encoder.set_max_size(self.remote.header_table_size);
+        Ok(())
+    }
+
+    fn receive(&mut self, mut frame: Frame) -> Result<Option<Frame>, Box<dyn Error>> {
+        if let Some(mut headers) = self.headers.take() {
+            if frame.kind != FrameKind::Continuation || frame.stream != headers.stream { Err(ErrorCode::ProtocolError)? }
+            headers.payload.extend(&frame.payload);
+            headers.flags |= frame.flags & FLAG_END_HEADERS;
+            return Ok(self.complete(headers))
+        }
+
+        match frame.kind {
+            FrameKind::Settings => {
+                if frame.stream != 0 { Err(ErrorCode::ProtocolError)? }
+                if !frame.has(FLAG_ACK) {
+                    self.apply(&frame.payload)?;
+                    self.write(Frame::new(FrameKind::Settings, FLAG_ACK, 0, Vec::new()))?;
+                }
+            }
+            FrameKind::Ping => {
+                if frame.payload.len() != 8 { Err(ErrorCode::FrameSizeError)? }
+                if !frame.has(FLAG_ACK) {
+                    self.write(Frame::new(FrameKind::Ping, FLAG_ACK, 0, frame.payload))?;
+                }
+            }
+            FrameKind::WindowUpdate if frame.stream == 0 => {
+                self.send_window += window_increment(&frame)? as i64;
+                if self.send_window > MAX_WINDOW_SIZE { Err(ErrorCode::FlowControlError)? }
+            }
+            FrameKind::Headers | FrameKind::PushPromise => {
+                if frame.stream == 0 { Err(ErrorCode::ProtocolError)? }
+                frame.payload = frame.content()?.to_vec();
+                frame.flags &= !(FLAG_PADDED | FLAG_PRIORITY);
+                return Ok(self.complete(frame))
+            }
+            FrameKind::Data => {
+                if frame.stream == 0 { Err(ErrorCode::ProtocolError)? }
+                // padding counts against flow control too, the connection window and the padding of the stream are
+                // replenished right away, the data of the stream once the caller took it (see Connection::replenish)
+                let length = frame.payload.len();
+                frame.payload = frame.content()?.to_vec();
+                frame.flags &= !FLAG_PADDED;
+                self.replenish(0, length)?;
+                if !frame.has(FLAG_END_STREAM) {
+                    self.replenish(frame.stream, length - frame.payload.len())?;
+                }
+                return Ok(Some(frame))
+            }
+            FrameKind::Continuation => Err(ErrorCode::ProtocolError)?,
+            // priorities are only advisory and unknown frames must be ignored
+            FrameKind::Priority | FrameKind::Unknown(_) => (),
+            _ => return Ok(Some(frame))
+        }
+
+        Ok(None)
+    }
+
+    // lets the peer send size more bytes on the stream, 0 for the connection
+    pub(crate) fn replenish(&mut self, stream: u32, size: usize) -> Result<(), Box<dyn Error>> {
+        if size == 0 { return Ok(()) }
+        self.write(Frame::new(FrameKind::WindowUpdate, 0, stream, (size as u32).to_be_bytes().to_vec()))
+    }
+
+    // a header block is only handed out once its last continuation arrived
+    fn complete(&mut self, headers: Frame) -> Option<Frame> {
+        if headers.has(FLAG_END_HEADERS) { return Some(headers) }
+        self.headers = Some(headers);
+        None
+    }
+
+    // sends as much of the payload as the flow control windows allow
+    pub(crate) fn write_outgoing(&mut self, stream: u32, outgoing: &mut Outgoing) -> Result<(), Box<dyn Error>> {
+        let initial = self.remote.initial_window_size as i64;
+        while outgoing.sent < outgoing.payload.len() {
+            let size = self.sendable(initial + outgoing.window, outgoing.payload.len() - outgoing.sent);
+            if size == 0 { break }
+            let end = outgoing.sent + size;
+            let last = !outgoing.open && end == outgoing.payload.len();
+            self.write_data(stream, &outgoing.payload[outgoing.sent..end], last)?;
+            outgoing.window -= size as i64;
+            outgoing.sent = end;
+            outgoing.ended = last;
+        }
+
+        // a streamed payload can end after everything read from it went out already
+        if !outgoing.open && !outgoing.ended && outgoing.sent == outgoing.payload.len() {
+            self.write_data(stream, &[], true)?;
+            outgoing.ended = true;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, ErrorCode> {
+        self.decoder.decode(block).or(Err(ErrorCode::CompressionError))
+    }
+}
+
+fn window_increment(frame: &Frame) -> Result<u32, ErrorCode> {
+    if frame.payload.len() != 4 { Err(ErrorCode::FrameSizeError)? }
+    match frame.u32_at(0)? & 0x7fff_ffff {
+        0 => Err(ErrorCode::ProtocolError),
+        increment => Ok(increment)
+    }
+}
+
+fn reset_code(frame: &Frame) -> ErrorCode {
+    frame.u32_at(0).map(ErrorCode::parse).unwrap_or(ErrorCode::ProtocolError)
+}
+
+// a payload being sent as flow control allows
+pub(crate) struct Outgoing {
+    payload: Vec<u8>,
+    sent: usize,
+    // window updates received minus data sent, the peer's initial window size is added on top
+    window: i64,
+    // more of the payload is still coming, see Outgoing::extend
+    open: bool,
+    // END_STREAM went out, with the headers when there is no payload
+    ended: bool
+}
+
+impl Outgoing {
+    pub(crate) fn new(payload: Vec<u8>) -> Self {
+        let ended = payload.is_empty();
+        Self { payload, sent: 0, window: 0, open: false, ended }
+    }
+
+    // appends to a streamed payload, dropping what was sent already
+    pub(crate) fn extend(&mut self, data: &[u8]) {
+        self.payload.drain(..self.sent);
+        self.sent = 0;
+        self.payload.extend(data);
+    }
+
+    pub(crate) fn done(&self) -> bool {
+        self.ended
+    }
+}
+
+// one request/response exchange on a client connection
+struct Stream {
+    index: usize,
+    outgoing: Outgoing,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>
+}
+
+pub struct Http2<S: Read + Write> {
+    connection: Connection<S>,
+    authority: String,
+    protocol: Protocol,
+    next_stream: u32,
+    // the highest stream the peer will still process after going away
+    last_stream: Option<u32>
+}
+
+pub type Https2<'a> = Http2<TlsStream<'a>>;
+
+impl<'a> Http2<TlsStream<'a>> {
+    pub fn connect(address: Address<'a>) -> Result<Self, Box<dyn Error>> {
+        let authority = address.authority(Protocol::Https);
+        let mut stream = TlsStream::connect_with(address, &RUSTLS_H2_CLIENT_CONFIG)?;
+        stream.handshake()?;
+        if stream.alpn_protocol() != Some(ALPN_H2) { Err(ErrorCode::Http11Required)? }
+        Http2::handshake(stream, authority, Protocol::Https)
+    }
+}
+
+impl Http2<TcpStream> {
+    // cleartext http/2 for servers we know to speak it
+    pub fn prior_knowledge(address: Address) -> Result<Self, Box<dyn Error>> {
+        let stream = address.connect()?;
+        Http2::handshake(stream, address.authority(Protocol::Http), Protocol::Http)
+    }
+
+    // asks an http/1.1 server to switch to h2c (rfc 7540 section 3.2), the response to the request then comes
+    // on stream 1 of the new connection, servers that don't switch answer over http/1.1 and no connection is returned
+    pub fn upgrade(address: Address, request: &mut Request) -> Result<(Option<Self>, Response<'static>), Box<dyn Error>> {
+        let mut stream = address.connect()?;
+        let settings = Settings::local();
+        request.version = Version::V11;
+        request.message.headers.add(Header::new("Host", address.authority(Protocol::Http)));
+        request.message.headers.add(Header::new("Connection", "Upgrade, HTTP2-Settings"));
+        request.message.headers.add(Header::new("Upgrade", UPGRADE_H2C));
+        request.message.headers.add(Header::new("HTTP2-Settings", base64::encode_config(settings.construct(), base64::URL_SAFE_NO_PAD)));
+        request.write_to(&mut stream)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut text = String::new();
+        let mut response = Response::parse_head(&mut reader, &mut text)?.into_owned();
+        if !matches!(response.status, Status::SwitchingProtocols) {
+            let mut payload = Vec::new();
+            Body::response(reader, &response, request.method)?.read_to_end(&mut payload)?;
+            response.message.payload = Payload::Identity(Cow::Owned(payload));
+            return Ok((None, response))
+        }
+
+        // whatever the server sent after the 101 already belongs to http/2
+        let incoming = reader.buffer().to_vec();
+        let mut stream = reader.into_inner();
+        stream.write_all(PREFACE)?;
+        let mut connection = Connection::new(stream);
+        connection.incoming = incoming;
+        connection.start()?;
+
+        let mut client = Self { connection, authority: address.authority(Protocol::Http), protocol: Protocol::Http, next_stream: 3, last_stream: None };
+        let mut streams = HashMap::new();
+        streams.insert(1, Stream { index: 0, outgoing: Outgoing::new(Vec::new()), headers: Vec::new(), body: Vec::new() });
+        let response = client.exchange(std::slice::from_mut(request), VecDeque::new(), streams)?.pop().ok_or(ErrorCode::InternalError)?;
+        Ok((Some(client), response?))
+    }
+}
+
+impl<S: Read + Write> Http2<S> {
+    pub fn handshake(mut stream: S, authority: String, protocol: Protocol) -> Result<Self, Box<dyn Error>> {
+        stream.write_all(PREFACE)?;
+        let mut connection = Connection::new(stream);
+        connection.start()?;
+
+        Ok(Self { connection, authority, protocol, next_stream: 1, last_stream: None })
+    }
+
+    pub fn send(&mut self, request: &mut Request) -> Result<Response<'static>, Box<dyn Error>> {
+        let response = self.send_all(std::slice::from_mut(request))?.pop().ok_or(ErrorCode::InternalError)?;
+        Ok(response?)
+    }
+
+    // every request gets its own stream and they are all in flight at once (up to the peer's limit),
+    // a reset stream only fails its own response
+    pub fn send_all(&mut self, requests: &mut [Request]) -> Result<Vec<Result<Response<'static>, ErrorCode>>, Box<dyn Error>> {
+        let queue = (0..requests.len()).collect();
+        self.exchange(requests, queue, HashMap::new())
+    }
+
+    // opens streams for the queued requests and reads until the streams already open have their responses too
+    fn exchange(&mut self, requests: &mut [Request], mut queue: VecDeque<usize>, mut streams: HashMap<u32, Stream>)
+    -> Result<Vec<Result<Response<'static>, ErrorCode>>, Box<dyn Error>> {
+        let mut responses: Vec<Option<Result<Response<'static>, ErrorCode>>> = requests.iter().map(|_| None).collect();
+
+        loop {
+            let limit = self.connection.remote.max_concurrent_streams.unwrap_or(u32::MAX) as usize;
+            while streams.len() < limit && self.last_stream.is_none() {
+                let index = match queue.pop_front() { Some(index) => index, None => break };
+                let id = self.open(&mut requests[index])?;
+                let outgoing = Outgoing::new(requests[index].message.payload.construct().to_vec());
+                streams.insert(id, Stream { index, outgoing, headers: Vec::new(), body: Vec::new() });
+            }
+
+            for (id, stream) in streams.iter_mut() {
+                self.connection.write_outgoing(*id, &mut stream.outgoing)?;
+            }
+            if streams.is_empty() {
+                if queue.is_empty() { break }
+                // the peer went away before these could be sent
+                for index in queue.drain(..) { responses[index] = Some(Err(ErrorCode::RefusedStream)); }
+                continue
+            }
+
+            let frame = self.connection.next()?;
+            let id = frame.stream;
+            let stream = match streams.get_mut(&id) {
+                Some(stream) => stream,
+                None => {
+                    match frame.kind {
+                        FrameKind::GoAway => {
+                            let last = frame.u32_at(0)? & 0x7fff_ffff;
+                            self.last_stream = Some(last);
+                            for id in streams.keys().copied().filter(|id| *id > last).collect::<Vec<_>>() {
+                                let stream = streams.remove(&id).unwrap();
+                                responses[stream.index] = Some(Err(ErrorCode::RefusedStream));
+                            }
+                        }
+                        FrameKind::PushPromise => Err(ErrorCode::ProtocolError)?,
+                        _ => ()
+                    }
+                    continue
+                }
+            };
+
+            let end_stream = frame.has(FLAG_END_STREAM);
+            match frame.kind {
+                FrameKind::Headers => {
+                    let headers = self.connection.decode(&frame.payload)?;
+                    let informational = headers.iter().any(|(n, v)| n == ":status" && v.starts_with('1'));
+                    if !informational { stream.headers.extend(headers); }
+                }
+                FrameKind::Data => {
+                    if !end_stream { self.connection.replenish(id, frame.payload.len())?; }
+                    stream.body.extend(&frame.payload)
+                }
+                FrameKind::WindowUpdate => stream.outgoing.window += window_increment(&frame)? as i64,
+                FrameKind::RstStream => {
+                    let stream = streams.remove(&id).unwrap();
+                    responses[stream.index] = Some(Err(reset_code(&frame)));
+                    continue
+                }
+                _ => ()
+            }
+
+            if end_stream {
+                let stream = streams.remove(&id).unwrap();
+                responses[stream.index] = Some(response(stream.headers, stream.body).or(Err(ErrorCode::ProtocolError)));
+            }
+        }
+
+        Ok(responses.into_iter().map(|r| r.unwrap_or(Err(ErrorCode::Cancel))).collect())
+    }
+
+    fn open(&mut self, request: &mut Request) -> Result<u32, Box<dyn Error>> {
+        let id = self.next_stream;
+        self.next_stream += 2;
+        request.version = Version::V2;
+
+        let mut headers = vec![
+            (":method".to_string(), request.method.to_string()),
+            (":scheme".to_string(), self.protocol.as_str().to_string()),
+            (":authority".to_string(), self.authority.clone()),
+            (":path".to_string(), request.target.to_string())
+        ];
+        fields(&request.message, &mut headers);
+
+        self.connection.write_headers(id, &headers, request.message.payload.construct().is_empty())?;
+        Ok(id)
+    }
+}
+
+// the regular fields of a message, with lowercase names and without hop-by-hop headers
+fn fields(message: &Message, headers: &mut Vec<(String, String)>) {
+    for header in message.headers.list() {
+        let name = header.name.to_lowercase();
+        if CONNECTION_HEADERS.contains(&name.as_str()) || name == "content-length" { continue }
+        headers.push((name, header.value.to_string()));
+    }
+
+    let length = message.payload.construct().len();
+    if length > 0 {
+        headers.push(("content-length".to_string(), length.to_string()));
+    }
+}
+
+fn response(headers: Vec<(String, String)>, body: Vec<u8>) -> Result<Response<'static>, Box<dyn Error>> {
+    let mut response = Response::new();
+    response.version = Version::V2;
+    let mut status = None;
+    for (name, value) in headers {
+        if name == ":status" {
+            status = Status::parse(&value);
+        } else if !name.starts_with(':') {
+            response.message.headers.append(Header::new(name, value));
+        }
+    }
+
+    response.status = status.ok_or(ParsingError::Status)?;
+    response.message.payload = Payload::Identity(Cow::Owned(body));
+    response.message.decode()?;
+    Ok(response)
+}
+
+
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+// the handler gets the request body whole, the peer can send at most this plus a window before the stream is reset
+const MAX_BODY_SIZE: usize = 1 << 22;
+// how many reads and answers may wait for the connection, the reader thread and handlers block beyond that
+const QUEUED_EVENTS: usize = 64;
+// how far the writer of a streamed response may get ahead of what went out
+const STREAM_BACKLOG: usize = DEFAULT_WINDOW_SIZE as usize;
+
+// a connection a server reads on a thread of its own, so it sleeps until there is something to do
+// the reader gets a handle to the socket and the raw bytes it reads are fed back through the connection
+pub trait Socket: Read + Write {
+    fn reader(&self) -> io::Result<TcpStream>;
+    // the plaintext of the raw bytes, appended to incoming
+    fn feed(&mut self, raw: &[u8], incoming: &mut Vec<u8>) -> io::Result<()>;
+}
+
+impl Socket for TcpStream {
+    fn reader(&self) -> io::Result<TcpStream> {
+        self.try_clone()
+    }
+
+    fn feed(&mut self, raw: &[u8], incoming: &mut Vec<u8>) -> io::Result<()> {
+        incoming.extend(raw);
+        Ok(())
+    }
+}
+
+impl Socket for rustls::StreamOwned<rustls::ServerSession, TcpStream> {
+    fn reader(&self) -> io::Result<TcpStream> {
+        self.sock.try_clone()
+    }
+
+    // the records are taken apart here, alerts and the like the session answers go out right away
+    fn feed(&mut self, mut raw: &[u8], incoming: &mut Vec<u8>) -> io::Result<()> {
+        use rustls::Session;
+        while !raw.is_empty() {
+            self.sess.read_tls(&mut raw)?;
+            self.sess.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            match self.sess.read_to_end(incoming) {
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                result => result?
+            };
+        }
+        while self.sess.wants_write() {
+            self.sess.write_tls(&mut self.sock)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Socket> Connection<S> {
+    pub(crate) fn feed(&mut self, raw: &[u8]) -> io::Result<()> {
+        self.stream.feed(raw, &mut self.incoming)
+    }
+}
+
+// what wakes a server connection up
+enum Event {
+    // raw bytes read off the socket, empty once the client closed it
+    Read(io::Result<Vec<u8>>),
+    // what the handler of a stream answered
+    Response(u32, Option<Response<'static>>),
+    // the next part of a streamed response, see Response::stream
+    Data(u32, Vec<u8>),
+    // a streamed response was read to the end, false when reading it failed
+    End(u32, bool)
+}
+
+// the part of a streamed response handed to the connection but not sent yet, None once the stream is gone
+// the writer waits while it is at STREAM_BACKLOG, so it never gets further ahead of the peer's windows than that
+#[derive(Default)]
+struct Backlog {
+    pending: Mutex<Option<usize>>,
+    changed: Condvar
+}
+
+impl Backlog {
+    // waits until size more bytes fit, false once the stream is gone
+    fn reserve(&self, size: usize) -> bool {
+        let mut pending = match self.pending.lock() { Ok(pending) => pending, Err(_) => return false };
+        while pending.is_some_and(|p| p + size > STREAM_BACKLOG) {
+            pending = match self.changed.wait(pending) { Ok(pending) => pending, Err(_) => return false };
+        }
+        match pending.as_mut() {
+            Some(pending) => *pending += size,
+            None => return false
+        }
+        true
+    }
+
+    fn release(&self, size: usize) {
+        if let Ok(mut pending) = self.pending.lock() {
+            if let Some(pending) = pending.as_mut() { *pending = pending.saturating_sub(size); }
+        }
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        if let Ok(mut pending) = self.pending.lock() { *pending = None; }
+        self.changed.notify_all();
+    }
+}
+
+// hands what a streamed response reads over to the connection, it fails once the stream is gone
+struct DataWriter {
+    id: u32,
+    sender: SyncSender<Event>,
+    backlog: Arc<Backlog>
+}
+
+impl Write for DataWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = buf.len().min(STREAM_BACKLOG);
+        if !self.backlog.reserve(size) || self.sender.send(Event::Data(self.id, buf[..size].to_vec())).is_err() {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))?
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type Handler = dyn Fn(Request<'static>) -> Option<Response<'static>> + Send + Sync;
+
+// a stream on a server connection, from the request coming in to the response going out
+struct Exchange {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    outgoing: Outgoing,
+    dispatched: bool,
+    responded: bool,
+    reset: bool,
+    // holds back the thread writing a streamed response, see Backlog
+    backlog: Arc<Backlog>
+}
+
+impl Exchange {
+    fn new(headers: Vec<(String, String)>) -> Self {
+        Self {
+            headers,
+            body: Vec::new(),
+            outgoing: Outgoing::new(Vec::new()),
+            dispatched: false,
+            responded: false,
+            reset: false,
+            backlog: Arc::new(Backlog { pending: Mutex::new(Some(0)), changed: Condvar::new() })
+        }
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        self.backlog.close();
+    }
+}
+
+struct Server<S: Socket> {
+    connection: Connection<S>,
+    exchanges: HashMap<u32, Exchange>,
+    last_stream: u32,
+    going_away: bool,
+    handler: Arc<Handler>,
+    sender: SyncSender<Event>,
+    receiver: Receiver<Event>
+}
+
+impl<S: Socket> Server<S> {
+    fn new<H>(stream: S, handler: H) -> Self
+    where H: Fn(Request<'static>) -> Option<Response<'static>> + Send + Sync + 'static {
+        let mut connection = Connection::new(stream);
+        connection.local.max_concurrent_streams = Some(MAX_CONCURRENT_STREAMS);
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_EVENTS);
+
+        Self {
+            connection,
+            exchanges: HashMap::new(),
+            last_stream: 0,
+            going_away: false,
+            handler: Arc::new(handler),
+            sender,
+            receiver
+        }
+    }
+
+    // a connection error is reported to the client before giving up
+    fn serve(&mut self) -> Result<(), Box<dyn Error>> {
+        let socket = self.connection.stream.reader()?;
+        self.read(socket.try_clone()?);
+        let mut result = self.run();
+        if let Err(e) = &result {
+            if let Some(error) = e.downcast_ref::<ErrorCode>() {
+                result = self.connection.go_away(self.last_stream, *error).and(result);
+            }
+        }
+
+        // the reader thread lets go of the connection once its read fails
+        let _ = socket.shutdown(Shutdown::Both);
+        result
+    }
+
+    fn read(&self, mut socket: TcpStream) {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; DEFAULT_FRAME_SIZE];
+            loop {
+                let read = match socket.read(&mut buffer) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    read => read.map(|size| buffer[..size].to_vec())
+                };
+                let closed = !matches!(&read, Ok(raw) if !raw.is_empty());
+                if sender.send(Event::Read(read)).is_err() || closed { return }
+            }
+        });
+    }
+
+    fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        self.connection.start()?;
+        let mut preface = false;
+
+        loop {
+            if !preface {
+                preface = self.connection.preface()?;
+            }
+            if preface {
+                while let Some(frame) = self.connection.buffered()? {
+                    self.receive(frame)?;
+                }
+            }
+            self.respond()?;
+            if self.going_away && self.exchanges.is_empty() { return Ok(()) }
+
+            match self.receiver.recv()? {
+                Event::Read(Ok(raw)) if raw.is_empty() => return Ok(()),
+                Event::Read(Ok(raw)) => match self.connection.feed(&raw) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                    result => result?
+                },
+                Event::Read(Err(e)) => Err(e)?,
+                Event::Response(id, response) => self.answer(id, response)?,
+                Event::Data(id, data) => if let Some(exchange) = self.exchanges.get_mut(&id) {
+                    exchange.outgoing.extend(&data);
+                },
+                Event::End(id, true) => if let Some(exchange) = self.exchanges.get_mut(&id) {
+                    exchange.outgoing.open = false;
+                },
+                Event::End(id, false) => if self.exchanges.remove(&id).is_some() {
+                    self.connection.reset(id, ErrorCode::InternalError)?
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, frame: Frame) -> Result<(), Box<dyn Error>> {
+        let id = frame.stream;
+        match frame.kind {
+            FrameKind::Headers => {
+                // the block is decoded even for refused streams to keep the compression state in sync
+                let headers = self.connection.decode(&frame.payload)?;
+                match self.exchanges.get_mut(&id) {
+                    Some(exchange) if !exchange.dispatched => exchange.headers.extend(headers),
+                    Some(_) => return self.connection.reset(id, ErrorCode::StreamClosed),
+                    None => {
+                        if id.is_multiple_of(2) || id <= self.last_stream { Err(ErrorCode::ProtocolError)? }
+                        self.last_stream = id;
+                        if self.going_away { return Ok(()) }
+                        if self.exchanges.len() >= MAX_CONCURRENT_STREAMS as usize {
+                            return self.connection.reset(id, ErrorCode::RefusedStream)
+                        }
+                        self.exchanges.insert(id, Exchange::new(headers));
+                    }
+                }
+            }
+            FrameKind::Data => match self.exchanges.get_mut(&id) {
+                Some(exchange) if !exchange.dispatched => {
+                    exchange.body.extend(&frame.payload);
+                    if exchange.body.len() > MAX_BODY_SIZE {
+                        self.exchanges.remove(&id);
+                        return self.connection.reset(id, ErrorCode::RefusedStream)
+                    }
+                    // the window only grows back while the body stays under the limit
+                    if !frame.has(FLAG_END_STREAM) && exchange.body.len() < MAX_BODY_SIZE {
+                        self.connection.replenish(id, frame.payload.len())?;
+                    }
+                }
+                _ => return self.connection.reset(id, ErrorCode::StreamClosed)
+            },
+            FrameKind::RstStream => {
+                match self.exchanges.get_mut(&id) {
+                    // the handler is still running, its response is dropped once it arrives
+                    Some(exchange) if exchange.dispatched && !exchange.responded => exchange.reset = true,
+                    Some(_) => { self.exchanges.remove(&id); }
+                    None => ()
+                }
+                return Ok(())
+            }
+            FrameKind::WindowUpdate => {
+                if let Some(exchange) = self.exchanges.get_mut(&id) {
+                    exchange.outgoing.window += window_increment(&frame)? as i64;
+                }
+                return Ok(())
+            }
+            FrameKind::GoAway => {
+                self.going_away = true;
+                return Ok(())
+            }
+            FrameKind::PushPromise => Err(ErrorCode::ProtocolError)?,
+            _ => return Ok(())
+        }
+
+        if frame.has(FLAG_END_STREAM) {
+            self.dispatch(id)?;
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(&mut self, id: u32) -> Result<(), Box<dyn Error>> {
+        let exchange = match self.exchanges.get_mut(&id) {
+            Some(exchange) => exchange,
+            None => return Ok(())
+        };
+        exchange.dispatched = true;
+
+        match request(std::mem::take(&mut exchange.headers), std::mem::take(&mut exchange.body)) {
+            Ok(request) => {
+                self.spawn(id, request);
+                Ok(())
+            }
+            Err(_) => {
+                self.exchanges.remove(&id);
+                self.connection.reset(id, ErrorCode::ProtocolError)
+            }
+        }
+    }
+
+    fn spawn(&self, id: u32, request: Request<'static>) {
+        let handler = self.handler.clone();
+        let sender = self.sender.clone();
+        let backlog = self.exchanges.get(&id).map(|e| e.backlog.clone()).unwrap_or_default();
+        thread::spawn(move || {
+            let accept_encoding = request.message.headers.get("accept-encoding").map(str::to_string);
+            // a panicking handler still has to close its stream
+            let response = panic::catch_unwind(AssertUnwindSafe(|| handler(request))).unwrap_or(None)
+                .map(|mut response| {
+                    response.compress(accept_encoding.as_deref());
+                    response
+                });
+
+            // a streamed payload follows the headers as it is read
+            let stream = response.as_ref().and_then(|r| r.stream.clone());
+            let _ = sender.send(Event::Response(id, response));
+            if let Some(stream) = stream {
+                let writer = DataWriter { id, sender: sender.clone(), backlog };
+                let written = panic::catch_unwind(AssertUnwindSafe(|| stream.write_to(writer).is_ok())).unwrap_or(false);
+                let _ = sender.send(Event::End(id, written));
+            }
+        });
+    }
+
+    // writes the response of a finished handler, its payload goes out with respond
+    fn answer(&mut self, id: u32, response: Option<Response<'static>>) -> Result<(), Box<dyn Error>> {
+        let exchange = match self.exchanges.get_mut(&id) {
+            Some(exchange) => exchange,
+            None => return Ok(())
+        };
+        if exchange.reset {
+            self.exchanges.remove(&id);
+            return Ok(())
+        }
+
+        match response {
+            // there is no switching protocols on http/2
+            Some(response) if response.is_upgrade() || matches!(response.status, Status::SwitchingProtocols) => {
+                self.exchanges.remove(&id);
+                self.connection.reset(id, ErrorCode::InternalError)
+            }
+            Some(response) => {
+                let mut headers = vec![(":status".to_string(), response.status.to_string())];
+                fields(&response.message, &mut headers);
+                // the thread of the handler sends a streamed payload along, see Server::spawn
+                let streamed = response.stream.is_some();
+                let payload = response.message.payload.construct().to_vec();
+                self.connection.write_headers(id, &headers, payload.is_empty() && !streamed)?;
+                exchange.outgoing.ended = payload.is_empty() && !streamed;
+                exchange.outgoing.open = streamed;
+                exchange.outgoing.payload = payload;
+                exchange.responded = true;
+                Ok(())
+            }
+            None => {
+                self.exchanges.remove(&id);
+                self.connection.reset(id, ErrorCode::Cancel)
+            }
+        }
+    }
+
+    // writes as much of the pending payloads as flow control allows
+    fn respond(&mut self) -> Result<(), Box<dyn Error>> {
+        for (id, exchange) in self.exchanges.iter_mut().filter(|(_, e)| e.responded) {
+            let sent = exchange.outgoing.sent;
+            self.connection.write_outgoing(*id, &mut exchange.outgoing)?;
+            exchange.backlog.release(exchange.outgoing.sent - sent);
+        }
+        self.exchanges.retain(|_, e| !(e.responded && e.outgoing.done()));
+        Ok(())
+    }
+}
+
+impl Http2<TcpStream> {
+    // cleartext http/2 with prior knowledge, the handler is the same one Http11::listen takes
+    pub fn listen<H>(address: Address, handler: &'static H) -> Result<thread::JoinHandle<()>, Box<dyn Error>>
+    where H: Fn(Request) -> Option<Response> + Sync {
+        Http2::listen_on(TcpListener::bind(address.to_string())?, handler)
+    }
+
+    // same as listen on a listener bound already, see Http11::listen_on
+    pub fn listen_on<H>(listener: TcpListener, handler: &'static H) -> Result<thread::JoinHandle<()>, Box<dyn Error>>
+    where H: Fn(Request) -> Option<Response> + Sync {
+        let handle = thread::spawn(move || {
+            for socket in listener.incoming().filter_map(|s| s.ok()) {
+                thread::spawn(move || {
+                    if let Err(e) = Http2::serve(socket, handler) {
+                        eprintln!("{}", e)
+                    }
+                });
+            }
+        });
+
+        Ok(handle)
+    }
+}
+
+impl<S: Socket> Http2<S> {
+    // serves a single connection until the client goes away, every stream is handled on its own thread
+    pub fn serve<H>(stream: S, handler: H) -> Result<(), Box<dyn Error>>
+    where H: Fn(Request<'static>) -> Option<Response<'static>> + Send + Sync + 'static {
+        Server::new(stream, handler).serve()
+    }
+
+    // continues a connection switched over from http/1.1 after answering the 101, the upgrade request
+    // becomes stream 1 and incoming holds whatever was read past it
+    pub fn serve_upgrade<H>(stream: S, incoming: Vec<u8>, settings: &[u8], request: Request<'static>, handler: H) -> Result<(), Box<dyn Error>>
+    where H: Fn(Request<'static>) -> Option<Response<'static>> + Send + Sync + 'static {
+        let mut server = Server::new(stream, handler);
+        server.connection.incoming = incoming;
+        server.connection.apply(settings)?;
+        server.last_stream = 1;
+        let mut exchange = Exchange::new(Vec::new());
+        exchange.dispatched = true;
+        server.exchanges.insert(1, exchange);
+        server.spawn(1, request);
+        server.serve()
+    }
+}
+
+// the decoded HTTP2-Settings of a request asking to switch to h2c (rfc 7540 section 3.2)
+// requests with a payload are answered over http/1.1, we don't carry it over to the first stream
+pub fn upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let headers = &request.message.headers;
+    if !has_token(headers, "upgrade", UPGRADE_H2C) { return None }
+    if !has_token(headers, "connection", "upgrade") || !has_token(headers, "connection", "http2-settings") { return None }
+    if !request.message.payload.raw().is_empty() { return None }
+    let settings = headers.get("http2-settings")?;
+    base64::decode_config(settings.trim(), base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn request(headers: Vec<(String, String)>, body: Vec<u8>) -> Result<Request<'static>, Box<dyn Error>> {
+    let mut request = Request::new();
+    request.version = Version::V2;
+    let mut method = None;
+    let mut target = None;
+    for (name, value) in headers {
+        match name.as_str() {
+            ":method" => method = Method::parse(&value),
+            ":path" => target = Target::parse(&value).map(Target::into_owned),
+            ":authority" => request.message.headers.add(Header::new("Host", value)),
+            ":scheme" => (),
+            _ if name.starts_with(':') => Err(ErrorCode::ProtocolError)?,
+            _ => request.message.headers.append(Header::new(name, value))
+        }
+    }
+
+    request.method = method.ok_or(ParsingError::Method)?;
+    request.target = target.ok_or(ParsingError::Head)?;
+    request.message.payload = Payload::Identity(Cow::Owned(body));
+    request.message.decode()?;
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiplex_requests() {
+        let (listener, port) = crate::tests::local();
+
+        let server = thread::spawn(move || {
+            let mut socket = listener.accept().unwrap().0;
+            let mut preface = [0; 24];
+            socket.read_exact(&mut preface).unwrap();
+            assert_eq!(&preface, PREFACE);
+
+            let mut connection = Connection::new(socket);
+            connection.start().unwrap();
+            let mut paths = HashMap::new();
+            while paths.len() < 2 {
+                let frame = connection.next().unwrap();
+                if frame.kind == FrameKind::Headers {
+                    let headers = connection.decode(&frame.payload).unwrap();
+                    let path = headers.into_iter().find(|(n, _)| n == ":path").unwrap().1;
+                    paths.insert(frame.stream, path);
+                }
+            }
+
+            // answer in reverse order, with the bodies split up and interleaved
+            let status = [(":status".to_string(), "200".to_string())];
+            connection.write_headers(3, &status, false).unwrap();
+            connection.write_headers(1, &status, false).unwrap();
+            for stream in [3, 1, 3, 1].iter() {
+                connection.write_data(*stream, paths[stream].as_bytes(), false).unwrap();
+            }
+            connection.write_data(3, &[], true).unwrap();
+            connection.write_data(1, &[], true).unwrap();
+            while connection.next().is_ok() { }
+        });
+
+        let mut client = Http2::prior_knowledge(Address::new("127.0.0.1", Some(port))).unwrap();
+        let mut requests = [Request::new(), Request::new()];
+        requests[0].target = Target::parse("/first").unwrap();
+        requests[1].target = Target::parse("/second").unwrap();
+        let responses = client.send_all(&mut requests).unwrap();
+        drop(client);
+        server.join().unwrap();
+
+        assert_eq!(responses[0].as_ref().unwrap().message.payload.text(), "/first/first");
+        assert_eq!(responses[1].as_ref().unwrap().message.payload.text(), "/second/second");
+    }
+
+    fn echo(request: Request) -> Option<Response> {
+        if request.target.location == "/missing" { return None }
+        let mut response = Response::new();
+        let text = format!("{} {}", request.target, request.message.payload.text());
+        response.message.payload = Payload::new(text.as_bytes()).into_owned();
+        Some(response)
+    }
+
+    fn host(request: Request) -> Option<Response> {
+        let mut response = Response::new();
+        response.message.payload = Payload::new(request.message.headers.get("host").unwrap_or_default().as_bytes()).into_owned();
+        Some(response)
+    }
+
+    #[test]
+    fn authority_from_address() {
+        assert_eq!(Address::new("example.com", Some(443)).authority(Protocol::Https), "example.com");
+        assert_eq!(Address::new("example.com", Some(8443)).authority(Protocol::Https), "example.com:8443");
+
+        let (listener, port) = crate::tests::local();
+        Http2::listen_on(listener, &host).unwrap();
+        let mut client = Http2::prior_knowledge(Address::new("127.0.0.1", Some(port))).unwrap();
+        assert_eq!(client.send(&mut Request::new()).unwrap().message.payload.text(), format!("127.0.0.1:{}", port));
+    }
+
+    #[test]
+    fn serve_concurrent_streams() {
+        let (listener, port) = crate::tests::local();
+
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap().0;
+            Http2::serve(socket, echo).unwrap();
+        });
+
+        let mut client = Http2::prior_knowledge(Address::new("127.0.0.1", Some(port))).unwrap();
+        let mut requests = [Request::new(), Request::new(), Request::new()];
+        requests[0].target = Target::parse("/first").unwrap();
+        requests[1].target = Target::parse("/second?key=value").unwrap();
+        requests[1].message.payload = Payload::new(b"body");
+        requests[2].target = Target::parse("/missing").unwrap();
+        let responses = client.send_all(&mut requests).unwrap();
+        drop(client);
+        server.join().unwrap();
+
+        assert_eq!(responses[0].as_ref().unwrap().message.payload.text(), "/first ");
+        assert_eq!(responses[1].as_ref().unwrap().message.payload.text(), "/second?key=value body");
+        assert_eq!(responses[2].as_ref().err(), Some(&ErrorCode::Cancel));
+    }
+
+    #[test]
+    fn refuse_oversized_bodies() {
+        let (listener, port) = crate::tests::local();
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap().0;
+            Http2::serve(socket, echo).unwrap();
+        });
+
+        let mut client = Http2::prior_knowledge(Address::new("127.0.0.1", Some(port))).unwrap();
+        let mut requests = [Request::new(), Request::new()];
+        requests[0].message.payload = Payload::new(&vec![b'a'; MAX_BODY_SIZE + 1]);
+        requests[1].message.payload = Payload::new(&vec![b'a'; WINDOW_SIZE as usize * 2]);
+        let responses = client.send_all(&mut requests).unwrap();
+        drop(client);
+        server.join().unwrap();
+
+        assert_eq!(responses[0].as_ref().err(), Some(&ErrorCode::RefusedStream));
+        assert_eq!(responses[1].as_ref().unwrap().message.payload.text().len(), WINDOW_SIZE as usize * 2 + 2);
+    }
+
+    fn large(_: Request) -> Option<Response> {
+        let mut response = Response::new();
+        response.stream(io::repeat(b'a').take(STREAM_BACKLOG as u64 * 4));
+        Some(response)
+    }
+
+    #[test]
+    fn stream_past_the_window() {
+        let (listener, port) = crate::tests::local();
+        let server = thread::spawn(move || {
+            let socket = listener.accept().unwrap().0;
+            Http2::serve(socket, large).unwrap();
+        });
+
+        let mut client = Http2::prior_knowledge(Address::new("127.0.0.1", Some(port))).unwrap();
+        let response = client.send(&mut Request::new()).unwrap();
+        drop(client);
+        server.join().unwrap();
+
+        assert_eq!(response.message.payload.raw().len(), STREAM_BACKLOG * 4);
+    }
+
+    #[test]
+    fn upgrade_from_http11() {
+        let (listener, port) = crate::tests::local();
+        Http11::listen_on(listener, &echo).unwrap();
+
+        let mut request = Request::new();
+        request.target = Target::parse("/upgraded").unwrap();
+        let (client, response) = Http2::upgrade(Address::new("127.0.0.1", Some(port)), &mut request).unwrap();
+        assert!(matches!(response.version, Version::V2));
+        assert_eq!(response.message.payload.text(), "/upgraded ");
+
+        // the switched connection takes further requests
+        let mut request = Request::new();
+        request.target = Target::parse("/next").unwrap();
+        assert_eq!(client.unwrap().send(&mut request).unwrap().message.payload.text(), "/next ");
+    }
+
+    #[test]
+    fn refuse_incomplete_upgrades() {
+        let settings = base64::encode_config(Settings::local().construct(), base64::URL_SAFE_NO_PAD);
+        let parse = |head: String| {
+            let mut text = String::new();
+            Request::parse(&mut io::Cursor::new(head), &mut text).unwrap().into_owned()
+        };
+        let upgrade = |connection: &str, payload: &str| parse(format!(
+            "POST / HTTP/1.1\r\nConnection: {}\r\nUpgrade: h2c\r\nHTTP2-Settings: {}\r\nContent-Length: {}\r\n\r\n{}",
+            connection, settings, payload.len(), payload
+        ));
+
+        assert!(upgrade_settings(&upgrade("Upgrade, HTTP2-Settings", "")).is_some());
+        assert!(upgrade_settings(&upgrade("Upgrade", "")).is_none());
+        assert!(upgrade_settings(&upgrade("keep-alive", "")).is_none());
+        assert!(upgrade_settings(&upgrade("Upgrade, HTTP2-Settings", "payload")).is_none());
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::{ HashMap, VecDeque };
+use lazy_static::lazy_static;
+use crate::def::*;
+
+// header compression for http/2 (rfc 7541)
+
+const DEFAULT_TABLE_SIZE: usize = 4096;
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"), (":path", "/index.html"),
+    (":scheme", "http"), (":scheme", "https"), (":status", "200"), (":status", "204"), (":status", "206"),
+    (":status", "304"), (":status", "400"), (":status", "404"), (":status", "500"), ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"), ("accept-language", ""), ("accept-ranges", ""), ("accept", ""),
+    ("access-control-allow-origin", ""), ("age", ""), ("allow", ""), ("authorization", ""), ("cache-control", ""),
+    ("content-disposition", ""), ("content-encoding", ""), ("content-language", ""), ("content-length", ""),
+    ("content-location", ""), ("content-range", ""), ("content-type", ""), ("cookie", ""), ("date", ""),
+    ("etag", ""), ("expect", ""), ("expires", ""), ("from", ""), ("host", ""), ("if-match", ""),
+    ("if-modified-since", ""), ("if-none-match", ""), ("if-range", ""), ("if-unmodified-since", ""),
+    ("last-modified", ""), ("link", ""), ("location", ""), ("max-forwards", ""), ("proxy-authenticate", ""),
+    ("proxy-authorization", ""), ("range", ""), ("referer", ""), ("refresh", ""), ("retry-after", ""),
+    ("server", ""), ("set-cookie", ""), ("strict-transport-security", ""), ("transfer-encoding", ""),
+    ("user-agent", ""), ("vary", ""), ("via", ""), ("www-authenticate", "")
+];
+
+// (code, length in bits) for every octet and eos (rfc 7541 appendix b)
+const HUFFMAN: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10),
+    (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6),
+    (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7),
+    (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14),
+    (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23),
+    (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23),
+    (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22),
+    (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22),
+    (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23),
+    (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21),
+    (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22),
+    (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27),
+    (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+lazy_static! {
+    static ref HUFFMAN_DECODE: HashMap<(u8, u32), u16> = HUFFMAN.iter().enumerate()
+        .map(|(symbol, &(code, length))| ((length, code), symbol as u16))
+        .collect();
+}
+
+// headers whose values should never end up in a compression table
+const SENSITIVE: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+struct Table {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize
+}
+
+impl Table {
+    fn new() -> Self {
+        Self { entries: VecDeque::new(), size: 0, max_size: DEFAULT_TABLE_SIZE }
+    }
+
+    // indices are 1-based, the dynamic table starts after the static one
+    fn get(&self, index: usize) -> Option<(&str, &str)> {
+        match index {
+            0 => None,
+            i if i <= STATIC_TABLE.len() => Some(STATIC_TABLE[i - 1]),
+            i => self.entries.get(i - STATIC_TABLE.len() - 1).map(|(n, v)| (n.as_str(), v.as_str()))
+        }
+    }
+
+    // (index, whether the value matched too)
+    fn find(&self, name: &str, value: &str) -> Option<(usize, bool)> {
+        let mut found = None;
+        let entries = STATIC_TABLE.iter().copied()
+            .chain(self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+        for (index, (n, v)) in entries.enumerate() {
+            if n != name { continue }
+            if v == value { return Some((index + 1, true)) }
+            found = found.or(Some((index + 1, false)));
+        }
+
+        found
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+        self.evict(self.max_size.saturating_sub(size));
+        // an entry larger than the table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.entries.push_front((name, value));
+        }
+    }
+
+    fn resize(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict(max_size);
+    }
+
+    fn evict(&mut self, limit: usize) {
+        while self.size > limit {
+            match self.entries.pop_back() {
+                Some((n, v)) => self.size -= n.len() + v.len() + ENTRY_OVERHEAD,
+                None => break
+            }
+        }
+    }
+}
+
+pub struct Decoder {
+    table: Table,
+    // the limit we advertised, the peer may only shrink the table below it
+    limit: usize
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self { table: Table::new(), limit: DEFAULT_TABLE_SIZE }
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, ParsingError> {
+        let mut headers = Vec::new();
+        let mut position = 0;
+
+        while position < block.len() {
+            let byte = block[position];
+            if byte & 0x80 != 0 {
+                let index = decode_integer(block, &mut position, 7)?;
+                let (name, value) = self.table.get(index).ok_or(ParsingError::Header)?;
+                headers.push((name.to_string(), value.to_string()));
+            } else if byte & 0xc0 == 0x40 {
+                let (name, value) = self.decode_literal(block, &mut position, 6)?;
+                self.table.insert(name.clone(), value.clone());
+                headers.push((name, value));
+            } else if byte & 0xe0 == 0x20 {
+                let size = decode_integer(block, &mut position, 5)?;
+                if size > self.limit { Err(ParsingError::Capacity)? }
+                self.table.resize(size);
+            } else {
+                // without indexing (0000) and never indexed (0001) only differ for intermediaries
+                headers.push(self.decode_literal(block, &mut position, 4)?);
+            }
+        }
+
+        Ok(headers)
+    }
+
+    fn decode_literal(&self, block: &[u8], position: &mut usize, prefix: u8) -> Result<(String, String), ParsingError> {
+        let index = decode_integer(block, position, prefix)?;
+        let name = match index {
+            0 => decode_string(block, position)?,
+            i => self.table.get(i).ok_or(ParsingError::Header)?.0.to_string()
+        };
+
+        Ok((name, decode_string(block, position)?))
+    }
+}
+
+pub struct Encoder {
+    table: Table,
+    // a table size update has to open the next block after the peer changed its limit
+    pending_size: Option<usize>
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self { table: Table::new(), pending_size: None }
+    }
+
+    pub fn set_max_size(&mut self, size: usize) {
+        let size = size.min(DEFAULT_TABLE_SIZE);
+        if size != self.table.max_size {
+            self.table.resize(size);
+            self.pending_size = Some(size);
+        }
+    }
+
+    // names have to be lowercase already
+    pub fn encode<'h, I: IntoIterator<Item = (&'h str, &'h str)>>(&mut self, headers: I, block: &mut Vec<u8>) {
+        if let Some(size) = self.pending_size.take() {
+            encode_integer(size, 5, 0x20, block);
+        }
+
+        for (name, value) in headers {
+            let sensitive = SENSITIVE.contains(&name);
+            match self.table.find(name, value) {
+                Some((index, true)) if !sensitive => encode_integer(index, 7, 0x80, block),
+                Some((index, _)) if sensitive => {
+                    encode_integer(index, 4, 0x10, block);
+                    encode_string(value, block);
+                }
+                found => {
+                    match found {
+                        Some((index, _)) => encode_integer(index, 6, 0x40, block),
+                        None => {
+                            block.push(0x40);
+                            encode_string(name, block);
+                        }
+                    }
+                    encode_string(value, block);
+                    if !sensitive {
+                        self.table.insert(name.to_string(), value.to_string());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn decode_integer(block: &[u8], position: &mut usize, prefix: u8) -> Result<usize, ParsingError> {
+    let mask = (1u16 << prefix) as usize - 1;
+    let mut value = *block.get(*position).ok_or(ParsingError::Header)? as usize & mask;
+    *position += 1;
+    if value < mask { return Ok(value) }
+
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*position).ok_or(ParsingError::Header)?;
+        *position += 1;
+        if shift > 28 { Err(ParsingError::Capacity)? }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 { return Ok(value) }
+    }
+}
+
+fn encode_integer(mut value: usize, prefix: u8, flags: u8, block: &mut Vec<u8>) {
+    let mask = (1u16 << prefix) as usize - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return
+    }
+
+    block.push(flags | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &[u8], position: &mut usize) -> Result<String, ParsingError> {
+    let huffman = block.get(*position).ok_or(ParsingError::Header)? & 0x80 != 0;
+    let length = decode_integer(block, position, 7)?;
+    let raw = block.get(*position..*position + length).ok_or(ParsingError::Header)?;
+    *position += length;
+
+    let bytes = if huffman { huffman_decode(raw)? } else { raw.to_vec() };
+    String::from_utf8(bytes).or(Err(ParsingError::Header))
+}
+
+fn encode_string(value: &str, block: &mut Vec<u8>) {
+    let bits: usize = value.bytes().map(|b| HUFFMAN[b as usize].1 as usize).sum();
+    let length = bits.div_ceil(8);
+    if length < value.len() {
+        encode_integer(length, 7, 0x80, block);
+        huffman_encode(value.as_bytes(), block);
+    } else {
+        encode_integer(value.len(), 7, 0, block);
+        block.extend(value.as_bytes());
+    }
+}
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, ParsingError> {
+    let mut decoded = Vec::new();
+    let mut code = 0u32;
+    let mut length = 0u8;
+
+    for byte in raw {
+        for shift in (0..8).rev() {
+            code = (code << 1) | ((byte >> shift) & 1) as u32;
+            length += 1;
+            if let Some(&symbol) = HUFFMAN_DECODE.get(&(length, code)) {
+                // eos inside the string is an error
+                if symbol == 256 { Err(ParsingError::Header)? }
+                decoded.push(symbol as u8);
+                code = 0;
+                length = 0;
+            } else if length > 30 {
+                Err(ParsingError::Header)?
+            }
+        }
+    }
+
+    // padding has to be a prefix of eos (all ones) shorter than a byte
+    if length > 7 || code != (1 << length) - 1 { Err(ParsingError::Header)? }
+    Ok(decoded)
+}
+
+fn huffman_encode(value: &[u8], block: &mut Vec<u8>) {
+    let mut buffer = 0u64;
+    let mut bits = 0;
+
+    for byte in value {
+        let (code, length) = HUFFMAN[*byte as usize];
+        buffer = (buffer << length) | code as u64;
+        bits += length;
+        while bits >= 8 {
+            bits -= 8;
+            block.push((buffer >> bits) as u8);
+        }
+    }
+
+    if bits > 0 {
+        // pad with the most significant bits of eos
+        block.push(((buffer << (8 - bits)) | (0xff >> bits)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // rfc 7541 c.4.1, first request with huffman coding
+    const REQUEST: [u8; 17] = [0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff];
+
+    #[test]
+    fn encode_decode_request() {
+        let headers = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+        let mut block = Vec::new();
+        Encoder::new().encode(headers.iter().copied(), &mut block);
+        assert_eq!(block, REQUEST);
+
+        let mut decoder = Decoder::new();
+        let decoded = decoder.decode(&REQUEST).unwrap();
+        assert_eq!(decoded[3], (":authority".to_string(), "www.example.com".to_string()));
+        // the authority is now the first dynamic entry
+        assert_eq!(decoder.decode(&[0xbe]).unwrap()[0].1, "www.example.com");
+    }
+}
@@ -1,10 +1,15 @@
-#![feature(str_split_as_str)]
-#![allow(unused_variables)]
-#![allow(unused_imports)]
-#![allow(dead_code)]
 
 #[cfg(test)]
 mod tests {
+    use std::net::TcpListener;
+
+    // a listener on a free port for the listen_on functions, it stays bound so no other test can take the port
+    pub(crate) fn local() -> (TcpListener, usize) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as usize;
+        (listener, port)
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
@@ -16,6 +21,15 @@ mod tests {
 pub mod def;
 pub mod message;
 pub mod http;
+pub mod hpack;
+pub mod h2;
+pub mod ws;
+pub mod sse;
+pub mod proxy;
+pub mod cookie;
+pub mod session;
+pub mod auth;
+pub mod retry;
 
 // idea: somehow preserve whole messages to store string in Response, Request as &str
 // todo: non-blocking & blocking headers, message, response, request (try to make them drop-in replacements)
@@ -5,16 +5,25 @@ use std::error::Error;
 use std::collections::HashMap;
 use std::io;
 use std::str;
-use std::io::{ Read, Write, BufRead, BufReader };
-use std::net::ToSocketAddrs;
-use mio::{ Events, Interest, Poll, Token, net::TcpStream };
-use lazy_static::lazy_static;
-use regex::Regex;
-use std::ops::Index;
+use std::io::{ Read, Write, BufRead, IoSlice };
+use std::ops::Range;
+use std::sync::{ Arc, Mutex };
 use crate::def::*;
 
-const NEWLINE: &'static str = "\r\n";
-
+const NEWLINE: &str = "\r\n";
+const HEAD_LIMIT: usize = 10000;
+const HEAD_CAPACITY: usize = 512;
+// payloads smaller than this aren't worth compressing
+const COMPRESSION_MINIMUM: usize = 1024;
+// the codings listeners compress with, in the order they prefer them
+const COMPRESSIONS: &[Encoding] = &[Encoding::Zstd, Encoding::Brotli, Encoding::GZip, Encoding::Deflate];
+// media types that are compressed already
+const COMPRESSED_TYPES: &[&str] = &[
+    "image/", "audio/", "video/", "font/woff", "application/zip", "application/gzip", "application/x-gzip",
+    "application/x-bzip2", "application/x-7z-compressed", "application/x-rar-compressed", "application/zstd"
+];
+
+#[derive(Clone)]
 pub struct Parameter<'a> {
     pub name: Cow<'a, str>,
     pub value: Option<Cow<'a, str>>
@@ -25,8 +34,7 @@ impl<'a> Parameter<'a> {
         Self { name: name.into(), value: value.map(|v| v.into()) }
     }
 
-    pub fn parse(parameter: &str) -> Option<Self> {
-        let parameter = unsafe { &*(parameter as *const str) };
+    pub fn parse(parameter: &'a str) -> Option<Self> {
         let pair: Vec<_> = parameter.split('=').collect();
         if pair.len() > 2 { return None }
         let value = if pair.len() == 1 { None } else { Some(Cow::Borrowed(pair[1])) };
@@ -34,56 +42,67 @@ impl<'a> Parameter<'a> {
         Some(Self { name: Cow::Borrowed(pair[0]), value })
     }
 
-    pub fn parse_many(parameters: &str) -> Vec<Self> {
-        parameters.split('&').filter_map(|p| Parameter::parse(p)).collect()
+    pub fn parse_many(parameters: &'a str) -> Vec<Self> {
+        parameters.split('&').filter_map(Parameter::parse).collect()
     }
 
     pub fn construct(&self) -> String {
-        match &self.value {
-            Some(value) => format!("{}={}", self.name, value),
-            None => self.name.to_string()
-        }
+        self.to_string()
+    }
+
+    pub fn into_owned(self) -> Parameter<'static> {
+        Parameter { name: Cow::Owned(self.name.into_owned()), value: self.value.map(|v| Cow::Owned(v.into_owned())) }
     }
 }
 
+#[derive(Clone)]
 pub struct Target<'a> {
     pub location: Cow<'a, str>,
     pub parameters: Vec<Parameter<'a>>
 }
 
-impl<'a> Target<'a> {
-    pub fn default() -> Self {
+impl<'a> Default for Target<'a> {
+    fn default() -> Self {
         Self { location: Cow::Borrowed("/"), parameters: Vec::new() }
     }
+}
 
-    // we need a way to minimize the unsafe pointer thing
-    // perhaps accept &'static str String
-    // target has a lifetime here so we probably should change that
-    // also i was thinking of removing unsafe with a struct that holds a &str or &string and a slice/range
-    // and as_ref method indexes the str
+impl<'a> Target<'a> {
     pub fn parse(target: &'a str) -> Option<Self> {
-        let mut parts = target.split('?');
-        let mut target = Target::default();
-        if let Some(location) = parts.next() {
-            return Some(Self { 
+        if target.is_empty() { return None }
+        match target.split_once('?') {
+            Some((location, parameters)) => Some(Self { 
                 location: Cow::Borrowed(location), 
-                parameters: Parameter::parse_many(parts.as_str()) })
+                parameters: Parameter::parse_many(parameters) }),
+            None => Some(Self { location: Cow::Borrowed(target), parameters: Vec::new() })
+        }
+    }
+
+    pub fn into_owned(self) -> Target<'static> {
+        Target { 
+            location: Cow::Owned(self.location.into_owned()), 
+            parameters: self.parameters.into_iter().map(Parameter::into_owned).collect() 
         }
+    }
+}
 
-        None
+impl<'a> Display for Parameter<'a> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={}", self.name, value),
+            None => write!(f, "{}", self.name)
+        }
     }
 }
 
 impl<'a> Display for Target<'a> {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        let mut target = self.location.to_string();
-        if !self.parameters.is_empty() {
-            let parameters = self.parameters.iter().map(|p| p.construct()).collect::<Vec<String>>().join("&");
-            target.push_str("?");
-            target.push_str(&parameters);
+        write!(f, "{}", self.location)?;
+        for (index, parameter) in self.parameters.iter().enumerate() {
+            write!(f, "{}{}", if index == 0 { '?' } else { '&' }, parameter)?;
         }
 
-        write!(f, "{}", target)
+        Ok(())
     }
 }
 
@@ -91,262 +110,851 @@ impl<'a> Display for Target<'a> {
 pub struct Headline<'a>(&'a str, &'a str, &'a str);
 
 impl<'a> Headline<'a> {
-    pub fn parse(reader: &mut impl BufRead, text: &mut String) -> Result<Self, Box<dyn Error>> {
-        let mut line = read_string_line(reader, text)?;
-        let mut parts = line.split(' ');
+    pub fn parse(line: &'a str) -> Result<Self, Box<dyn Error>> {
+        let mut parts = line.splitn(3, ' ');
         let first = parts.next().ok_or(ParsingError::Head)?;
         let second = parts.next().ok_or(ParsingError::Head)?;
-        let third = parts.as_str();
-        if third.len() == 0 { Err(ParsingError::Head)?; }
+        let third = parts.next().unwrap_or("");
+        if third.is_empty() { Err(ParsingError::Head)?; }
 
         Ok(Headline(first, second, third))
     }
 
     pub fn construct(first: impl Display, second: impl Display, third: impl Display) -> Vec<u8> {
-        format!("{} {} {}{}", first, second, third, NEWLINE).into_bytes()
+        let mut headline = Vec::new();
+        Headline::write_to(&mut headline, first, second, third).unwrap();
+        headline
+    }
+
+    pub fn write_to<W: Write>(writer: &mut W, first: impl Display, second: impl Display, third: impl Display) -> io::Result<()> {
+        write!(writer, "{} {} {}{}", first, second, third, NEWLINE)
     }
 }
 
 // pub for debug
+#[derive(Clone)]
 pub struct Request<'a> {
     pub method: Method,
     pub target: Target<'a>,
     pub version: Version,
     pub message: Message<'a>,
-    text: String
+    text: Cow<'a, str>
+}
+
+impl<'a> Default for Request<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Request<'a> {
     pub fn new() -> Self {
-        Self { method: Method::GET,  target: Target::default(), version: Version::V11, message: Message::new(), text: String::new() }
+        Self { method: Method::GET,  target: Target::default(), version: Version::V11, message: Message::new(), text: Cow::Borrowed("") }
     }
 
-    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
-        let mut text = String::new();
-        let Headline(method, target, version) = Headline::parse(reader, &mut text)?;
+    // the head is read into text and everything but the payload borrows from it
+    pub fn parse<R: BufRead>(reader: &mut R, text: &'a mut String) -> Result<Self, Box<dyn Error>> {
+        let text = read_head(reader, text)?;
+        let mut lines = text.split(NEWLINE);
+        let Headline(method, target, version) = Headline::parse(lines.next().unwrap_or(""))?;
 
         Ok(Self {
             method: Method::parse(method).ok_or(ParsingError::Method)?,
             target: Target::parse(target).ok_or(ParsingError::Head)?,
             version: Version::parse(version).ok_or(ParsingError::Version)?,
-            message: Message::parse(reader, &mut text)?,
-            text
+            message: Message::parse(reader, Headers::parse(lines), Framing::Length(0))?,
+            text: Cow::Borrowed(text)
         })
     }
 
+    pub fn into_owned(self) -> Request<'static> {
+        Request {
+            method: self.method,
+            target: self.target.into_owned(),
+            version: self.version,
+            message: self.message.into_owned(),
+            text: Cow::Owned(self.text.into_owned())
+        }
+    }
+
     pub fn text(&self) -> String {
         format!("{}{}", self.text, String::from_utf8_lossy(self.message.payload.raw()))
     }
 
     pub fn construct(&mut self) -> Vec<u8> {
         let mut request = Vec::new();
-        request.extend(Headline::construct(self.method, &self.target, self.version));
-        request.extend(self.message.construct());
-
+        self.write_to(&mut request).unwrap();
         request
     }
+
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.message.set_length();
+        let head = self.head()?;
+        write_all_vectored(writer, &mut [IoSlice::new(&head), IoSlice::new(&self.message.payload.construct())])
+    }
+
+    // the payload is streamed from the source instead, chunked when its length is unknown
+    pub fn write_source<W: Write>(&mut self, writer: &mut W, source: Source) -> io::Result<()> {
+        match source.length {
+            Some(length) => self.message.headers.add(Header::new("Content-Length", length.to_string())),
+            None => self.message.headers.add(Header::from(TransferEncoding::Chunked))
+        }
+        if !source.encodings.is_empty() {
+            self.message.headers.add(Header::from_many(&source.encodings));
+        }
+
+        writer.write_all(&self.head()?)?;
+        source.write_to(writer)?;
+        writer.flush()
+    }
+
+    fn head(&self) -> io::Result<Vec<u8>> {
+        let mut head = Vec::with_capacity(HEAD_CAPACITY);
+        Headline::write_to(&mut head, self.method, &self.target, self.version)?;
+        self.message.headers.write_to(&mut head)?;
+        head.extend(NEWLINE.as_bytes());
+        Ok(head)
+    }
 }
 
+#[derive(Clone)]
 pub struct Response<'a> {
     pub version: Version,
     pub status: Status,
     pub message: Message<'a>,
-    text: String
+    text: Cow<'a, str>,
+    // takes over the connection once the response is written
+    pub(crate) upgrade: Option<Upgrade>,
+    // written chunked in place of the payload, see Response::stream
+    pub(crate) stream: Option<Stream>
+}
+
+impl<'a> Default for Response<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Response<'a> {
     pub fn new() -> Self {
-        Self { version: Version::V11, status: Status::Ok, message: Message::new(), text: String::new() }
+        Self { version: Version::V11, status: Status::Ok, message: Message::new(), text: Cow::Borrowed(""), upgrade: None, stream: None }
     }
 
-    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
-        let mut text = String::new();
-        let Headline(version, status, message) = Headline::parse(reader, &mut text)?;
+    pub fn parse<R: BufRead>(reader: &mut R, text: &'a mut String) -> Result<Self, Box<dyn Error>> {
+        Self::parse_for(reader, text, Method::GET)
+    }
+
+    // the response to a request with the method, which decides whether there is a payload at all
+    pub fn parse_for<R: BufRead>(reader: &mut R, text: &'a mut String, method: Method) -> Result<Self, Box<dyn Error>> {
+        let mut response = Self::parse_head(reader, text)?;
+        if !response.is_bodiless(method) {
+            response.message = Message::parse(reader, response.message.headers, Framing::Close)?;
+        }
+        Ok(response)
+    }
+
+    // 1xx, 204, 304, responses to HEAD and tunnels opened by CONNECT end with the head, whatever their headers say
+    // (rfc 7230 section 3.3.3)
+    pub fn is_bodiless(&self, method: Method) -> bool {
+        let code = self.status.code();
+        matches!(method, Method::HEAD) || (matches!(method, Method::CONNECT) && (200..300).contains(&code)) || matches!(code, 100..=199 | 204 | 304)
+    }
+
+    // the payload is left in the reader so it can be streamed through Body
+    pub fn parse_head<R: BufRead>(reader: &mut R, text: &'a mut String) -> Result<Self, Box<dyn Error>> {
+        let text = read_head(reader, text)?;
+        let mut lines = text.split(NEWLINE);
+        let Headline(version, status, message) = Headline::parse(lines.next().unwrap_or(""))?;
         let status = Status::parse(status).ok_or(ParsingError::Status)?;
         if !status.validate_message(message) { Err(ParsingError::Status)?; }
         
         Ok(Self {
             version: Version::parse(version).ok_or(ParsingError::Version)?,
-            message: Message::parse(reader, &mut text)?,
-            status, text
+            message: Message { headers: Headers::parse(lines), ..Message::new() },
+            status, text: Cow::Borrowed(text), upgrade: None, stream: None
         })
     }
 
+    // a 101 switching to the protocol, the callback gets the connection (plain or tls) once the response is flushed
+    pub fn switching_protocols<F>(protocol: &str, callback: F) -> Response<'static>
+    where F: FnOnce(Upgraded) + Send + 'static {
+        let mut response = Response::new();
+        response.status = Status::SwitchingProtocols;
+        response.message.headers.add(Header::new("Connection", "Upgrade"));
+        response.message.headers.add(Header::new("Upgrade", protocol.to_string()));
+        response.on_upgrade(callback);
+        response
+    }
+
+    // the listener stops speaking http on the connection after writing the response and hands it over
+    pub fn on_upgrade<F>(&mut self, callback: F)
+    where F: FnOnce(Upgraded) + Send + 'static {
+        self.upgrade = Some(Upgrade::new(callback));
+    }
+
+    pub fn is_upgrade(&self) -> bool {
+        self.upgrade.is_some()
+    }
+
+    // the payload is read from the reader as the response is written, every read going out as a chunk
+    pub fn stream<R: Read + Send + 'static>(&mut self, reader: R) {
+        self.message.headers.remove("content-length");
+        self.message.headers.add(Header::from(TransferEncoding::Chunked));
+        self.stream = Some(Stream::new(reader));
+    }
+
+    // encodes the payload with the coding negotiated from the Accept-Encoding of the request
+    // payloads encoded already, in compressed formats or too small to gain anything are left alone
+    pub fn compress(&mut self, accept_encoding: Option<&str>) {
+        if self.is_upgrade() || matches!(self.status, Status::SwitchingProtocols) { return }
+        let headers = &self.message.headers;
+        if headers.get("content-encoding").is_some() { return }
+        if headers.get_all("cache-control").any(|c| c.to_lowercase().contains("no-transform")) { return }
+        if headers.get("content-type").is_some_and(is_compressed) { return }
+        if self.stream.is_none() {
+            match &self.message.payload {
+                Payload::Identity(content) if content.len() >= COMPRESSION_MINIMUM => (),
+                _ => return
+            }
+        }
+
+        // caches have to tell the representations apart, whether this one got compressed or not
+        if !self.message.headers.get_all("vary").any(|v| v.split(',').any(|f| f.trim() == "*" || f.trim().eq_ignore_ascii_case("accept-encoding"))) {
+            self.message.headers.append(Header::new("Vary", "Accept-Encoding"));
+        }
+
+        let encoding = match accept_encoding.and_then(|accept| Encoding::negotiate(accept, COMPRESSIONS)) {
+            Some(encoding) => encoding,
+            None => return
+        };
+        match &mut self.stream {
+            Some(stream) => stream.encodings.push(encoding),
+            None => match self.message.payload.encode(Encodings::from(vec![encoding])) {
+                Ok(payload) => {
+                    self.message.payload = payload;
+                    self.message.headers.remove("content-length");
+                }
+                Err(_) => return
+            }
+        }
+        self.message.headers.add(Header::from(encoding));
+    }
+
+    pub fn into_owned(self) -> Response<'static> {
+        Response { 
+            version: self.version, 
+            status: self.status, 
+            message: self.message.into_owned(), 
+            text: Cow::Owned(self.text.into_owned()),
+            upgrade: self.upgrade,
+            stream: self.stream
+        }
+    }
+
     pub fn text(&self) -> String {
         format!("{}{}", self.text, String::from_utf8_lossy(self.message.payload.raw()))
     }
 
     pub fn construct(&mut self) -> Vec<u8> {
         let mut response = Vec::new();
-        response.extend(Headline::construct(self.version, self.status, self.status.message()));
-        response.extend(self.message.construct());
-
+        self.write_to(&mut response).unwrap();
         response
     }
+
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        if let Some(stream) = self.stream.take() {
+            writer.write_all(&self.head()?)?;
+            stream.write_to(ChunkedWriter::new(&mut *writer))?.finish()?;
+            return Ok(())
+        }
+
+        self.message.set_length();
+        // an empty payload still needs framing, or a client on a kept-alive connection waits for it to close
+        let framed = matches!(self.status.code(), 100..=199 | 204 | 304) || self.message.headers.get("transfer-encoding").is_some();
+        if !framed && self.message.payload.construct().is_empty() {
+            self.message.headers.add(Header::new("Content-Length", "0"));
+        }
+        let head = self.head()?;
+        write_all_vectored(writer, &mut [IoSlice::new(&head), IoSlice::new(&self.message.payload.construct())])
+    }
+
+    fn head(&self) -> io::Result<Vec<u8>> {
+        let mut head = Vec::with_capacity(HEAD_CAPACITY);
+        Headline::write_to(&mut head, self.version, self.status, self.status.message())?;
+        self.message.headers.write_to(&mut head)?;
+        head.extend(NEWLINE.as_bytes());
+        Ok(head)
+    }
+}
+
+fn is_compressed(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    media_type != "image/svg+xml" && COMPRESSED_TYPES.iter().any(|t| media_type.starts_with(t))
 }
 
-pub struct Headers<'a>(HashMap<String, Header<'a>>);
+// fields that may repeat (like Set-Cookie) keep every value in the order they came in
+#[derive(Clone)]
+pub struct Headers<'a>(HashMap<String, Vec<Header<'a>>>);
+
+impl<'a> Default for Headers<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl<'a> Headers<'a> {
     pub fn new() -> Self {
         Self(HashMap::new())
     }
 
+    // replaces every field with the same name
     pub fn add(&mut self, header: Header<'a>) {
-        self.0.insert(header.name.to_lowercase(), header);
+        self.0.insert(header.name.to_lowercase(), vec![header]);
+    }
+
+    pub fn append(&mut self, header: Header<'a>) {
+        self.0.entry(header.name.to_lowercase()).or_default().push(header);
+    }
+
+    pub fn remove(&mut self, normalized: &str) {
+        self.0.remove(normalized);
     }
 
     pub fn have<T: ToHeader>(&self, to_header: T) -> bool {
-        if let Some(header) = self.0.get(T::normalized()) { 
-            return header.value.to_lowercase() == to_header.value();
+        if let Some(value) = self.get(T::normalized()) { 
+            return value.to_lowercase() == to_header.value();
         }
 
         false
     }
     
-    pub fn list(&self) -> std::collections::hash_map::Values<String, Header> {
-        self.0.values()
+    pub fn list(&self) -> impl Iterator<Item = &Header<'a>> {
+        self.0.values().flatten()
     }
 
+    // the last value when the field repeats
     pub fn get(&self, normalized: &str) -> Option<&str> {
-        self.0.get(normalized).map(|h| &h.value as &str)
+        self.0.get(normalized).and_then(|h| h.last()).map(|h| &h.value as &str)
     }
 
-    pub fn parse<R: BufRead>(reader: &mut R, text: &mut String) -> Result<Self, Box<dyn Error>> {
+    pub fn get_all(&self, normalized: &str) -> impl Iterator<Item = &str> {
+        self.0.get(normalized).into_iter().flatten().map(|h| &h.value as &str)
+    }
+
+    pub fn parse<I: Iterator<Item = &'a str>>(lines: I) -> Self {
         let mut headers = Self::new();
-        
-        loop {
-            let line = read_string_line(reader, text)?;
-            if line.len() == 0 || text.len() > 10000 { break; }
-            if let Some(header) = Header::parse(line) {
-                headers.add(header);
-            }
+        for header in lines.filter_map(Header::parse) {
+            headers.append(header);
         }
 
-        Ok(headers)
+        headers
+    }
+
+    pub fn into_owned(self) -> Headers<'static> {
+        Headers(self.0.into_iter().map(|(name, headers)| (name, headers.into_iter().map(Header::into_owned).collect())).collect())
     }
 
     pub fn construct(&self) -> Vec<u8> {
         let mut headers = Vec::new();
+        self.write_to(&mut headers).unwrap();
+        headers
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         for header in self.list() {
-            headers.extend(header.construct().as_bytes());
-            headers.extend(NEWLINE.as_bytes());
+            write!(writer, "{}{}", header, NEWLINE)?;
         }
 
-        headers
+        Ok(())
     }
 }
 
 impl<'a> Display for Headers<'a> {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.0.values().fold(String::new(), |acc, h| acc + &h.construct() + "\r\n"))
+        for header in self.list() {
+            write!(f, "{}{}", header, NEWLINE)?;
+        }
+
+        Ok(())
     }
 }
 
+#[derive(Clone)]
 pub struct Message<'a> {
     pub headers: Headers<'a>,
-    pub payload: Payload<'a>
+    pub payload: Payload<'a>,
+    // the content-codings taken off the payload when it was parsed
+    // Content-Encoding and Content-Length go with them, so the message can be written out again as is
+    pub decoded: Vec<Encoding>
+}
+
+impl<'a> Default for Message<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Message<'a> {
     pub fn new() -> Self {
-        Self { headers: Headers::new(), payload: Payload::default() }
+        Self { headers: Headers::new(), payload: Payload::default(), decoded: Vec::new() }
     }
 
-    fn parse<R: BufRead>(mut reader: &mut R, text: &mut String) -> Result<Self, Box<dyn Error>> {
-        let mut message = Message::new();
-        message.headers = Headers::parse(reader, text)?;
-        if let Some(content_length) = message.headers.get("content-length") {
-            let content_length_usize = content_length.parse::<usize>().or(Err(ParsingError::Payload))?;
-            message.payload = Payload::read(reader, content_length_usize)?;
-        } else if message.headers.have(TransferEncoding::Chunked) {
-            message.payload = Payload::dechunk(reader)?;
-        }
+    // fallback frames messages without a length or chunked encoding, see Framing::of
+    fn parse<R: BufRead>(reader: &mut R, headers: Headers<'a>, fallback: Framing) -> Result<Self, Box<dyn Error>> {
+        let mut message = Message { headers, ..Message::new() };
+        message.payload = match Framing::of(&message.headers, fallback)? {
+            Framing::Length(length) => Payload::read(reader, length)?,
+            Framing::Chunked(_) => Payload::dechunk(reader)?,
+            Framing::Close => {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                Payload::Identity(Cow::Owned(content))
+            }
+        };
 
-        if let Some(encodings) = message.headers.get("content-encoding") {
-            message.payload = message.payload.decode(Encodings::parse(encodings))?;
+        message.decode()?;
+        Ok(message)
+    }
+
+    pub(crate) fn decode(&mut self) -> io::Result<()> {
+        if let Some(encodings) = self.headers.get("content-encoding") {
+            let encodings = Encodings::parse(encodings);
+            self.decoded = encodings.list().to_vec();
+            self.payload = self.payload.decode(encodings)?;
+            if !self.decoded.is_empty() {
+                self.headers.remove("content-encoding");
+                self.headers.remove("content-length");
+            }
         }
 
-        Ok(message)
+        Ok(())
+    }
+
+    pub fn into_owned(self) -> Message<'static> {
+        Message { headers: self.headers.into_owned(), payload: self.payload.into_owned(), decoded: self.decoded }
     }
 
     pub fn construct(&mut self) -> Vec<u8> {
-        let payload = self.payload.construct();
-        if payload.len() > 0 {
-            self.headers.add(Header::new("Content-Length", payload.len().to_string())); // contentlength could be toheader
+        let mut message = Vec::new();
+        self.write_to(&mut message).unwrap();
+        message
+    }
+
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.set_length();
+        self.headers.write_to(writer)?;
+        writer.write_all(NEWLINE.as_bytes())?;
+        writer.write_all(&self.payload.construct())
+    }
+
+    fn set_length(&mut self) {
+        let length = self.payload.construct().len();
+        let chunked = matches!(self.payload, Payload::Chunked { .. });
+        if chunked {
+            self.headers.remove("transfer-encoding");
+        }
+        if length > 0 || chunked {
+            self.headers.add(Header::new("Content-Length", length.to_string())); // contentlength could be toheader
         }
+    }
+}
 
-        let mut message = self.headers.construct();
-        message.extend(NEWLINE.as_bytes());
-        message.extend(payload);
-        message
+// writes every slice, falling back to one slice per call on writers without vectored support
+fn write_all_vectored<W: Write>(writer: &mut W, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::WriteZero))?,
+            Ok(size) => IoSlice::advance_slices(&mut slices, size),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e)?
+        }
     }
+
+    Ok(())
 }
 
-fn read_string_line(reader: &mut impl BufRead, text: &mut String) -> io::Result<&'static str> {
-    let size = reader.read_line(text)?;
+// reads the headline and headers up to the empty line
+fn read_head<'a>(reader: &mut impl BufRead, text: &'a mut String) -> io::Result<&'a str> {
+    text.clear();
+    loop {
+        let size = reader.read_line(text)?;
+        // the peer closed before sending anything, e.g. a keep-alive connection that timed out
+        if size == 0 && text.is_empty() { Err(io::Error::from(io::ErrorKind::UnexpectedEof))? }
+        if size < NEWLINE.len() { Err(io::Error::new(io::ErrorKind::InvalidData, ""))? }
+        if size == NEWLINE.len() || text.len() > HEAD_LIMIT { break }
+    }
+
+    Ok(text)
+}
+
+fn read_line(reader: &mut impl BufRead, buffer: &mut Vec<u8>) -> io::Result<Range<usize>> {
+    let start = buffer.len();
+    let size = reader.read_until(b'\n', buffer)?;
     if size < NEWLINE.len() { Err(io::Error::new(io::ErrorKind::InvalidData, ""))? }
-    let line = unsafe { &*(&text[text.len() - size .. text.len() - NEWLINE.len()] as *const str) };
-    Ok(line)
+    Ok(start..buffer.len() - NEWLINE.len())
+}
+
+fn read_exact(reader: &mut impl BufRead, buffer: &mut Vec<u8>, size: usize) -> io::Result<Range<usize>> {
+    let start = buffer.len();
+    buffer.resize(start + size, 0);
+    reader.read_exact(&mut buffer[start..])?;
+    Ok(start..buffer.len())
+}
+
+enum Framing {
+    Length(usize),
+    // bytes left in the current chunk, 0 when the next chunk size line is due
+    Chunked(usize),
+    Close
+}
+
+impl Framing {
+    // chunked wins over a length (rfc 7230 section 3.3.3), but a message with both is refused
+    // since whoever framed it by the length would disagree on where it ends
+    // without either a request has no payload (fallback Length(0)) and a response runs until the connection closes (Close)
+    // only a transfer-coding list ending with chunked frames a request, lengths have to agree across fields and values
+    fn of(headers: &Headers, fallback: Framing) -> Result<Self, ParsingError> {
+        let codings: Vec<String> = headers.get_all("transfer-encoding")
+            .flat_map(|v| v.split(','))
+            .map(|c| c.trim().to_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect();
+        let length = Framing::length(headers)?;
+
+        match codings.split_last() {
+            Some(_) if length.is_some() => Err(ParsingError::Payload),
+            Some((last, rest)) if last == "chunked" && !rest.iter().any(|c| c == "chunked") => Ok(Framing::Chunked(0)),
+            // the coding can't be told apart from the payload, a response just goes on until the connection closes
+            Some((last, _)) if last != "chunked" && matches!(fallback, Framing::Close) => Ok(Framing::Close),
+            Some(_) => Err(ParsingError::Payload),
+            None => Ok(length.map(Framing::Length).unwrap_or(fallback))
+        }
+    }
+
+    fn length(headers: &Headers) -> Result<Option<usize>, ParsingError> {
+        let mut length = None;
+        for value in headers.get_all("content-length").flat_map(|v| v.split(',')).map(str::trim) {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) { Err(ParsingError::Payload)? }
+            let value = value.parse::<usize>().or(Err(ParsingError::Payload))?;
+            if length.is_some_and(|l| l != value) { Err(ParsingError::Payload)? }
+            length = Some(value);
+        }
+
+        Ok(length)
+    }
+}
+
+struct Framed<R> {
+    reader: R,
+    framing: Framing
+}
+
+impl<R: BufRead> Framed<R> {
+    fn read_chunk_size(&mut self) -> io::Result<usize> {
+        let mut line = Vec::new();
+        let range = read_line(&mut self.reader, &mut line)?;
+        let size = chunk_size(&line[range])?;
+        if size == 0 {
+            skip_trailers(&mut self.reader, &mut line)?;
+        }
+
+        Ok(size)
+    }
+}
+
+// chunk-size [ ; chunk-ext ], the extensions are ignored
+fn chunk_size(line: &[u8]) -> io::Result<usize> {
+    let size = line.split(|&b| b == b';').next().and_then(|s| str::from_utf8(s).ok()).unwrap_or_default().trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Err(io::Error::new(io::ErrorKind::InvalidData, ParsingError::Payload))?
+    }
+    usize::from_str_radix(size, 16).or(Err(io::Error::new(io::ErrorKind::InvalidData, ParsingError::Payload)))
+}
+
+// the trailer section after the last chunk, up to the empty line ending the message
+fn skip_trailers(reader: &mut impl BufRead, buffer: &mut Vec<u8>) -> io::Result<()> {
+    while !read_line(reader, buffer)?.is_empty() { }
+    Ok(())
+}
+
+impl<R: BufRead> Read for Framed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.framing {
+            Framing::Close => self.reader.read(buf),
+            Framing::Length(remaining) => {
+                if remaining == 0 { return Ok(0) }
+                let limit = remaining.min(buf.len());
+                let size = self.reader.read(&mut buf[..limit])?;
+                if size == 0 { Err(io::Error::from(io::ErrorKind::UnexpectedEof))? }
+                self.framing = Framing::Length(remaining - size);
+                Ok(size)
+            }
+            Framing::Chunked(mut remaining) => {
+                if remaining == 0 {
+                    remaining = self.read_chunk_size()?;
+                    if remaining == 0 {
+                        self.framing = Framing::Length(0);
+                        return Ok(0)
+                    }
+                }
+
+                let limit = remaining.min(buf.len());
+                let size = self.reader.read(&mut buf[..limit])?;
+                if size == 0 { Err(io::Error::from(io::ErrorKind::UnexpectedEof))? }
+                remaining -= size;
+                if remaining == 0 {
+                    let mut newline = [0; 2];
+                    self.reader.read_exact(&mut newline)?;
+                }
+                self.framing = Framing::Chunked(remaining);
+                Ok(size)
+            }
+        }
+    }
+}
+
+// streams the payload of a message, removing the framing and decoding the content on the fly
+pub struct Body<'r> {
+    decoder: Decoder<Framed<Box<dyn BufRead + 'r>>>,
+    encodings: Vec<Encoding>
+}
+
+impl<'r> Body<'r> {
+    // the payload of a request, empty without a length or chunked framing
+    pub fn new<R: BufRead + 'r>(reader: R, headers: &Headers) -> Result<Self, Box<dyn Error>> {
+        let framing = Framing::of(headers, Framing::Length(0))?;
+        Self::framed(reader, headers, framing)
+    }
+
+    // the payload of the response to a request with the method
+    // one without a length or chunked framing runs until the connection closes
+    pub fn response<R: BufRead + 'r>(reader: R, response: &Response, method: Method) -> Result<Self, Box<dyn Error>> {
+        let headers = &response.message.headers;
+        let framing = if response.is_bodiless(method) {
+            Framing::Length(0)
+        } else {
+            Framing::of(headers, Framing::Close)?
+        };
+        Self::framed(reader, headers, framing)
+    }
+
+    fn framed<R: BufRead + 'r>(reader: R, headers: &Headers, framing: Framing) -> Result<Self, Box<dyn Error>> {
+        let framed = Framed { reader: Box::new(reader) as Box<dyn BufRead>, framing };
+        let encodings = Encodings::parse(headers.get("content-encoding").unwrap_or_default());
+        Ok(Self { decoder: encodings.decoder(framed)?, encodings: encodings.list().to_vec() })
+    }
+
+    // the content-codings being taken off, empty when the payload is read as is
+    pub fn encodings(&self) -> &[Encoding] {
+        &self.encodings
+    }
+}
+
+impl<'r> Read for Body<'r> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.decoder.read(buf)?;
+        if size == 0 && !buf.is_empty() {
+            // consume whatever framing is left so the connection can be reused
+            io::copy(self.decoder.get_mut(), &mut io::sink())?;
+        }
+
+        Ok(size)
+    }
+}
+
+// a connection a listener can hand over, plain or tls
+pub trait Transport: Read + Write + Send { }
+
+impl<T: Read + Write + Send> Transport for T { }
+
+// the connection after a 101 response, what the listener already read past the request comes first
+pub struct Upgraded {
+    buffered: io::Cursor<Vec<u8>>,
+    stream: Box<dyn Transport>
+}
+
+impl Upgraded {
+    pub fn new(buffered: Vec<u8>, stream: Box<dyn Transport>) -> Self {
+        Self { buffered: io::Cursor::new(buffered), stream }
+    }
+
+    // the bytes not read yet from the buffer and the connection itself
+    pub fn into_parts(self) -> (Vec<u8>, Box<dyn Transport>) {
+        let position = self.buffered.position() as usize;
+        let mut buffered = self.buffered.into_inner();
+        buffered.drain(..position);
+        (buffered, self.stream)
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return self.buffered.read(buf)
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+type Takeover = Box<dyn FnOnce(Upgraded) + Send>;
+
+// shared so responses stay cloneable, only the first run gets the connection
+#[derive(Clone)]
+pub(crate) struct Upgrade(Arc<Mutex<Option<Takeover>>>);
+
+impl Upgrade {
+    pub(crate) fn new<F: FnOnce(Upgraded) + Send + 'static>(callback: F) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(callback)))))
+    }
+
+    pub(crate) fn run(&self, upgraded: Upgraded) {
+        let callback = self.0.lock().ok().and_then(|mut callback| callback.take());
+        if let Some(callback) = callback {
+            callback(upgraded);
+        }
+    }
 }
 
-// this has to be done somewhere else
-// fn read_string_line_nonblocking(reader: &mut impl BufRead, text: &mut String) -> io::Result<&'static str> {
-//     let mut size;
-//     loop {
-//         match reader.read_line(text) {
-//             Ok(s) => { size = s; break },
-//             Err(e) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::WouldBlock => continue,
-//             Err(e) => Err(e)?
-//         }
-//     }
-//     if size < NEWLINE.len() { Err(io::Error::new(io::ErrorKind::InvalidData, ""))? }
-//     let line = unsafe { &*(&text[text.len() - size .. text.len() - NEWLINE.len()] as *const str) };
-//     Ok(line)
-// }
+type StreamReader = Box<dyn Read + Send>;
 
-fn read_exact_string(reader: &mut impl BufRead, text: &mut String, size: usize) -> io::Result<&'static str> {
-    let mut buffer = vec![0 as u8; size];
-    reader.read_exact(&mut buffer)?;
-    let text_length = text.len();
-    text.push_str(&String::from_utf8_lossy(buffer.as_ref()));
-    Ok(unsafe { &*(&text[text_length..] as *const str) })
+// the reader of a streamed response, shared like Upgrade and only the first write gets it
+#[derive(Clone)]
+pub(crate) struct Stream {
+    reader: Arc<Mutex<Option<StreamReader>>>,
+    // applied on the way out, see Response::compress
+    encodings: Vec<Encoding>
 }
 
-fn read_line(reader: &mut impl BufRead, buffer: &mut Vec<u8>) -> io::Result<&'static [u8]> {
-    let size = reader.read_until('\n' as u8, buffer)?;
-    if size < NEWLINE.len() { Err(io::Error::new(io::ErrorKind::InvalidData, ""))? } // return some io error here
-    let line = unsafe { &*(&buffer[buffer.len() - size .. buffer.len() - NEWLINE.len()] as *const [u8]) };
-    Ok(line)
+impl Stream {
+    fn new<R: Read + Send + 'static>(reader: R) -> Self {
+        Self { reader: Arc::new(Mutex::new(Some(Box::new(reader)))), encodings: Vec::new() }
+    }
+
+    // flushes after every read so a slow reader (e.g. events) doesn't wait in the encoder for the next one
+    pub(crate) fn write_to<W: Write>(self, writer: W) -> io::Result<W> {
+        let mut encoder = Encodings::from(self.encodings).encoder(writer)?;
+        let reader = self.reader.lock().ok().and_then(|mut reader| reader.take());
+        if let Some(mut reader) = reader {
+            let mut buffer = [0; 8192];
+            loop {
+                let size = match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(size) => size,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e)?
+                };
+                encoder.write_all(&buffer[..size])?;
+                encoder.flush()?;
+            }
+        }
+
+        encoder.finish()
+    }
 }
 
-fn read_exact(reader: &mut impl BufRead, buffer: &mut Vec<u8>, size: usize) -> io::Result<&'static [u8]> {
-    let mut exact_buffer = vec![0 as u8; size];
-    reader.read_exact(&mut exact_buffer)?;
-    let buffer_length = buffer.len();
-    buffer.append(&mut exact_buffer);
-    Ok(unsafe { &*(&buffer[buffer_length..] as *const [u8]) })
+// a payload read from anywhere (e.g. a file) as the message is written
+pub struct Source<'s> {
+    reader: Box<dyn Read + 's>,
+    length: Option<usize>,
+    encodings: Vec<Encoding>
 }
 
-fn read_str_line(reader: &mut impl BufRead, buffer: &mut Vec<u8>) -> io::Result<&'static str> {
-    Ok(str::from_utf8(read_line(reader, buffer)?).or(Err(io::Error::new(io::ErrorKind::InvalidData, "")))?)
+impl<'s> Source<'s> {
+    pub fn new<R: Read + 's>(reader: R, length: Option<usize>) -> Self {
+        Self { reader: Box::new(reader), length, encodings: Vec::new() }
+    }
+
+    // encoded on the way out, the encoded length isn't known so it goes chunked
+    pub fn encode(mut self, encodings: Encodings) -> Self {
+        self.encodings.extend(encodings.list());
+        self.length = None;
+        self
+    }
+
+    pub fn file(file: std::fs::File) -> io::Result<Self> {
+        let length = file.metadata()?.len() as usize;
+        Ok(Self::new(file, Some(length)))
+    }
+
+    pub fn length(&self) -> Option<usize> {
+        self.length
+    }
+
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        match self.length {
+            Some(length) => {
+                let written = io::copy(&mut self.reader.take(length as u64), writer)?;
+                if written < length as u64 { Err(io::Error::from(io::ErrorKind::UnexpectedEof))? }
+            }
+            None => {
+                let mut encoder = Encodings::from(self.encodings).encoder(ChunkedWriter::new(writer))?;
+                io::copy(&mut { self.reader }, &mut encoder)?;
+                encoder.finish()?.finish()?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-fn read_exact_str(reader: &mut impl BufRead, buffer: &mut Vec<u8>, size: usize) -> io::Result<&'static str> {
-    Ok(str::from_utf8(read_exact(reader, buffer, size)?).or(Err(io::Error::new(io::ErrorKind::InvalidData, "")))?)
+// every write becomes a chunk, finish writes the terminating chunk
+pub struct ChunkedWriter<W: Write> {
+    writer: W
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(b"0\r\n\r\n")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would terminate the payload
+        if buf.is_empty() { return Ok(0) }
+        self.writer.write_all(format!("{:x}{}", buf.len(), NEWLINE).as_bytes())?;
+        self.writer.write_all(buf)?;
+        self.writer.write_all(NEWLINE.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
 }
 
 // we need to optimize this 
+#[derive(Clone)]
 pub enum Payload<'a> {
     Identity(Cow<'a, [u8]>),
-    Chunked { content:  Vec<u8>, chunks: Vec<&'a [u8]> }
+    // content holds the raw chunked body, chunks are the ranges of chunk data within it
+    Chunked { content: Vec<u8>, chunks: Vec<Range<usize>> }
 }
 
-impl<'a> Payload<'a> {
-    pub fn default() -> Self {
+impl<'a> Default for Payload<'a> {
+    fn default() -> Self {
         Self::Identity(Cow::Borrowed(&[]))
     }
+}
 
+impl<'a> Payload<'a> {
     pub fn new(content: &[u8]) -> Self {
         Self::Identity(Cow::Owned(content.to_owned()))
     }
@@ -360,7 +968,7 @@ impl<'a> Payload<'a> {
     // optimize
     pub fn chunks(&self) -> Vec<&[u8]> { // this should be a iterator if only iteator could own the values
         match self {
-            Self::Chunked { chunks, .. } => chunks.clone(),
+            Self::Chunked { content, chunks } => chunks.iter().map(|c| &content[c.clone()]).collect(),
             Self::Identity(content) => vec![content.as_ref()]
         }
     }
@@ -369,16 +977,24 @@ impl<'a> Payload<'a> {
         let mut content = Vec::new();
         let mut chunks = Vec::new();
         loop {
-            let line = read_str_line(reader, &mut content)?;
-            if line.len() < 1 || line == "0" { break }
-            let chunk_size = usize::from_str_radix(line, 16).or(Err(""))?;
+            let line = read_line(reader, &mut content)?;
+            let chunk_size = chunk_size(&content[line])?;
+            if chunk_size == 0 { break }
             let chunk = read_exact(reader, &mut content, chunk_size + NEWLINE.len())?;
-            chunks.push(chunk);
+            chunks.push(chunk.start..chunk.end - NEWLINE.len());
         }
+        skip_trailers(reader, &mut content)?;
 
         Ok(Self::Chunked { content, chunks })
     }
 
+    pub fn into_owned(self) -> Payload<'static> {
+        match self {
+            Self::Identity(content) => Payload::Identity(Cow::Owned(content.into_owned())),
+            Self::Chunked { content, chunks } => Payload::Chunked { content, chunks }
+        }
+    }
+
     pub fn raw(&self) -> &[u8] {
         match self {
             Self::Identity(content) => content,
@@ -390,26 +1006,294 @@ impl<'a> Payload<'a> {
         self.chunks().iter().fold(String::new(), |acc, c| acc + &String::from_utf8_lossy(c))
     }
 
-    pub fn construct(&self) -> &[u8] {
+    // chunked payloads are joined, they go out with a length (see Message::set_length)
+    pub fn construct(&self) -> Cow<'_, [u8]> {
         match self {
-            Self::Identity(content) => content,
-            // perhaps we could implement that later but idk if its even worth it
-            // maybe only if we read from an iterator and constructed chunks on the go 
-            Self::Chunked { .. } => panic!("chunked construct")
+            Self::Identity(content) => Cow::Borrowed(content),
+            Self::Chunked { .. } => Cow::Owned(self.chunks().concat())
         }
     }
 
-    // optimize
+    // the chunks are decoded where they are, without joining them first
     pub fn decode(&self, encodings: Encodings) -> io::Result<Self> {
         let decoded = match self {
             Self::Identity(content) => encodings.decode(content)?,
-            Self::Chunked { chunks, .. } => encodings.decode(&chunks.concat())?
+            Self::Chunked { content, chunks } => {
+                let mut decoded = Vec::new();
+                encodings.decoder(ChunkReader { content, chunks: chunks.iter(), current: &[] })?.read_to_end(&mut decoded)?;
+                decoded
+            }
         };
         
         Ok(Self::Identity(Cow::Owned(decoded)))
     }
 
     pub fn encode(&self, encodings: Encodings) -> io::Result<Self> {
-        Ok(Self::Identity(Cow::Owned(encodings.encode(self.construct())?)))
+        let encoded = match self {
+            Self::Identity(content) => encodings.encode(content)?,
+            Self::Chunked { content, chunks } => {
+                let mut encoder = encodings.encoder(Vec::new())?;
+                io::copy(&mut ChunkReader { content, chunks: chunks.iter(), current: &[] }, &mut encoder)?;
+                encoder.finish()?
+            }
+        };
+
+        Ok(Self::Identity(Cow::Owned(encoded)))
     }
-}
\ No newline at end of file
+}
+
+// reads the data of one chunk after another out of a chunked payload
+struct ChunkReader<'c> {
+    content: &'c [u8],
+    chunks: std::slice::Iter<'c, Range<usize>>,
+    current: &'c [u8]
+}
+
+impl<'c> Read for ChunkReader<'c> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.next() {
+                Some(chunk) => self.current = &self.content[chunk.clone()],
+                None => return Ok(0)
+            }
+        }
+
+        self.current.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parse_request() {
+        let mut reader = Cursor::new("GET /path?a=1&b HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello");
+        let mut text = String::new();
+        let request = Request::parse(&mut reader, &mut text).unwrap();
+        assert_eq!(request.target.location, "/path");
+        assert_eq!(request.target.parameters.len(), 2);
+        assert_eq!(request.target.parameters[1].value, None);
+        assert_eq!(request.message.headers.get("host"), Some("example.com"));
+        assert_eq!(request.message.payload.raw(), b"hello");
+    }
+
+    #[test]
+    fn parse_chunked_response() {
+        let mut reader = Cursor::new("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+        let mut text = String::new();
+        let response = Response::parse(&mut reader, &mut text).unwrap().into_owned();
+        assert_eq!(response.message.payload.text(), "Wikipedia");
+    }
+
+    #[test]
+    fn chunk_extensions_and_trailers() {
+        // the trailers are consumed with the payload, the next request on the connection starts right after
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(raw);
+        let mut text = String::new();
+        assert_eq!(Request::parse(&mut reader, &mut text).unwrap().message.payload.text(), "Wikipedia");
+        let mut text = String::new();
+        assert_eq!(Request::parse(&mut reader, &mut text).unwrap().target.location, "/next");
+
+        let mut reader = Cursor::new(raw);
+        let mut text = String::new();
+        let headers = Request::parse(&mut reader, &mut text).unwrap().into_owned().message.headers;
+        let mut reader = Cursor::new(raw);
+        let mut text = String::new();
+        read_head(&mut reader, &mut text).unwrap();
+        let mut payload = String::new();
+        Body::new(&mut reader, &headers).unwrap().read_to_string(&mut payload).unwrap();
+        assert_eq!(payload, "Wikipedia");
+        assert_eq!(&raw[reader.position() as usize..], "GET /next HTTP/1.1\r\n\r\n");
+
+        // only hex digits make a size
+        let mut text = String::new();
+        let signed = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+4\r\nWiki\r\n0\r\n\r\n";
+        assert!(Request::parse(&mut Cursor::new(signed), &mut text).is_err());
+    }
+
+    #[test]
+    fn owned_request_outlives_buffer() {
+        let request = {
+            let mut reader = Cursor::new("POST /upload?id=7 HTTP/1.1\r\nContent-Length: 2\r\n\r\nok");
+            let mut text = String::new();
+            Request::parse(&mut reader, &mut text).unwrap().into_owned()
+        };
+        let replay = request.clone();
+        let request = std::thread::spawn(move || request).join().unwrap();
+        assert_eq!(request.target.parameters[0].value.as_deref(), Some("7"));
+        assert_eq!(replay.message.payload.raw(), b"ok");
+    }
+
+    #[test]
+    fn replay_chunked_request() {
+        let mut reader = Cursor::new("POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+        let mut text = String::new();
+        let request = Request::parse(&mut reader, &mut text).unwrap().into_owned();
+        let mut replay = request.clone();
+        let mut written = Vec::new();
+        replay.write_to(&mut written).unwrap();
+
+        let mut reader = Cursor::new(written);
+        let mut text = String::new();
+        let replayed = Request::parse(&mut reader, &mut text).unwrap();
+        assert!(!replayed.message.headers.have(TransferEncoding::Chunked));
+        assert_eq!(replayed.message.headers.get("content-length"), Some("9"));
+        assert_eq!(replayed.message.payload.raw(), b"Wikipedia");
+    }
+
+    #[test]
+    fn stream_chunked_gzip_body() {
+        let encoded = Encodings::parse("gzip").encode(b"streamed body").unwrap();
+        let mut raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Encoding: gzip\r\n\r\n".to_vec();
+        for chunk in encoded.chunks(7) {
+            raw.extend(format!("{:x}\r\n", chunk.len()).as_bytes());
+            raw.extend(chunk);
+            raw.extend(NEWLINE.as_bytes());
+        }
+        raw.extend(b"0\r\n\r\n");
+
+        let mut reader = Cursor::new(raw);
+        let mut text = String::new();
+        let response = Response::parse_head(&mut reader, &mut text).unwrap();
+        let mut body = String::new();
+        let mut streamed = Body::response(&mut reader, &response, Method::GET).unwrap();
+        assert_eq!(streamed.encodings(), &[Encoding::GZip]);
+        streamed.read_to_string(&mut body).unwrap();
+        drop(streamed);
+        assert_eq!(body, "streamed body");
+        assert_eq!(reader.position() as usize, reader.get_ref().len());
+    }
+
+    #[test]
+    fn frame_bodies() {
+        // both framings at once is refused instead of picking one
+        let smuggled = "POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let mut text = String::new();
+        assert!(Request::parse(&mut Cursor::new(smuggled), &mut text).is_err());
+        let mut headers = Headers::new();
+        headers.add(Header::new("Content-Length", "4"));
+        headers.add(Header::from(TransferEncoding::Chunked));
+        assert!(Body::new(Cursor::new(""), &headers).is_err());
+
+        // the last transfer-coding decides, a request without chunked there can't be framed
+        let mut text = String::new();
+        let gzipped = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+        assert_eq!(Request::parse(&mut Cursor::new(gzipped), &mut text).unwrap().message.payload.text(), "ok");
+        for refused in ["Transfer-Encoding: chunked, gzip", "Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked", "Content-Length: 5, 6", "Content-Length: 5\r\nContent-Length: 6", "Content-Length: +5"] {
+            let raw = format!("POST / HTTP/1.1\r\n{}\r\n\r\nhello", refused);
+            let mut text = String::new();
+            assert!(Request::parse(&mut Cursor::new(raw), &mut text).is_err(), "{}", refused);
+        }
+        let mut text = String::new();
+        assert_eq!(Request::parse(&mut Cursor::new("POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\nhello"), &mut text).unwrap().message.payload.raw(), b"hello");
+
+        // a response with some other coding last goes on until the connection closes
+        let mut text = String::new();
+        let response = Response::parse(&mut Cursor::new("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\nraw"), &mut text).unwrap();
+        assert_eq!(response.message.payload.raw(), b"raw");
+
+        // no payload after these, the next response follows right away on a kept-alive connection
+        let mut reader = Cursor::new("HTTP/1.1 204 No Content\r\n\r\nHTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nnext");
+        for (status, method) in [(204, Method::GET), (304, Method::GET), (200, Method::HEAD)] {
+            let mut text = String::new();
+            let response = Response::parse_head(&mut reader, &mut text).unwrap().into_owned();
+            assert_eq!(response.status.code(), status);
+            let mut payload = Vec::new();
+            Body::response(&mut reader, &response, method).unwrap().read_to_end(&mut payload).unwrap();
+            assert!(payload.is_empty());
+        }
+        assert_eq!(reader.position() as usize, reader.get_ref().len() - 4);
+
+        // without framing the payload of a response runs until the connection closes
+        let mut reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\nuntil closed");
+        let mut text = String::new();
+        let response = Response::parse_head(&mut reader, &mut text).unwrap().into_owned();
+        let mut payload = String::new();
+        Body::response(&mut reader, &response, Method::GET).unwrap().read_to_string(&mut payload).unwrap();
+        assert_eq!(payload, "until closed");
+    }
+
+    #[test]
+    fn write_chunked_source() {
+        let mut request = Request::new();
+        request.method = Method::POST;
+        let mut written = Vec::new();
+        request.write_source(&mut written, Source::new(Cursor::new("uploaded file"), None)).unwrap();
+
+        let mut reader = Cursor::new(written);
+        let mut text = String::new();
+        let request = Request::parse(&mut reader, &mut text).unwrap();
+        assert!(request.message.headers.have(TransferEncoding::Chunked));
+        assert_eq!(request.message.payload.text(), "uploaded file");
+    }
+
+    #[test]
+    fn write_encoded_source() {
+        let mut request = Request::new();
+        request.method = Method::POST;
+        let source = Source::new(Cursor::new("uploaded file"), Some(13)).encode(Encodings::parse("gzip, zstd"));
+        let mut written = Vec::new();
+        request.write_source(&mut written, source).unwrap();
+
+        let mut reader = Cursor::new(written);
+        let mut text = String::new();
+        let request = Request::parse(&mut reader, &mut text).unwrap();
+        assert!(request.message.headers.have(TransferEncoding::Chunked));
+        assert_eq!(request.message.headers.get("content-length"), None);
+        assert_eq!(request.message.decoded, vec![Encoding::GZip, Encoding::Zstd]);
+        assert_eq!(request.message.payload.text(), "uploaded file");
+    }
+
+    #[test]
+    fn write_response() {
+        let mut response = Response::new();
+        response.message.payload = Payload::new(b"body");
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody");
+
+        let mut written = Vec::new();
+        Response::new().write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let mut response = Response::new();
+        response.status = Status::NoContent;
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let supported = [Encoding::Brotli, Encoding::GZip, Encoding::Deflate];
+        assert_eq!(Encoding::negotiate("gzip, deflate, br", &supported), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate("gzip;q=1.0, br;q=0.5", &supported), Some(Encoding::GZip));
+        assert_eq!(Encoding::negotiate("deflate, *;q=0.1", &supported), Some(Encoding::Deflate));
+        assert_eq!(Encoding::negotiate("*", &supported), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate("br;q=0, *", &supported), Some(Encoding::GZip));
+        assert_eq!(Encoding::negotiate("identity", &supported), None);
+        assert_eq!(Encoding::negotiate("", &supported), None);
+        assert_eq!(Encoding::negotiate("gzip;q=0.8, zstd", &[Encoding::GZip, Encoding::Zstd]), Some(Encoding::Zstd));
+    }
+
+    #[test]
+    fn encode_chunked_payload() {
+        let mut reader = Cursor::new("4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+        let payload = Payload::dechunk(&mut reader).unwrap();
+        let encoded = payload.encode(Encodings::parse("br")).unwrap();
+        assert_eq!(encoded.decode(Encodings::parse("br")).unwrap().raw(), b"Wikipedia");
+    }
+
+    #[test]
+    fn zstd_layers() {
+        let encodings = Encodings::parse("zstd, gzip");
+        assert_eq!(encodings.list(), &[Encoding::Zstd, Encoding::GZip]);
+        let encoded = encodings.encode(b"layered payload").unwrap();
+        assert_eq!(encodings.decode(&encoded).unwrap(), b"layered payload");
+        let mut decoded = Vec::new();
+        encodings.decoder(Cursor::new(encoded)).unwrap().read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, b"layered payload");
+    }
+}
@@ -0,0 +1,192 @@
+use std::error::Error;
+use std::io::{ self, ErrorKind };
+use std::time::{ Duration, SystemTime };
+use rand::Rng;
+use crate::def::*;
+use crate::message::*;
+
+// when the client tries a request again, see Http::exchange
+
+// errors where the connection went away before a response came
+const RESET: &[ErrorKind] = &[
+    ErrorKind::ConnectionReset,
+    ErrorKind::ConnectionAborted,
+    ErrorKind::BrokenPipe,
+    ErrorKind::UnexpectedEof
+];
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // attempts in total, 1 never retries
+    pub attempts: usize,
+    // the backoff doubles from the base with every attempt, up to max_delay
+    pub base: Duration,
+    pub max_delay: Duration,
+    // retries that would end past the deadline aren't made
+    pub timeout: Duration,
+    pub statuses: Vec<u16>
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self {
+            attempts: 3,
+            base: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            statuses: vec![
+                Status::BadGateway.code(),
+                Status::ServiceUnavailable.code(),
+                Status::GatewayTimeout.code()
+            ]
+        }
+    }
+
+    pub fn none() -> Self {
+        Self { attempts: 1, ..RetryPolicy::new() }
+    }
+
+    // full jitter, anything between zero and the capped exponential delay
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31) as u32;
+        let delay = self.base.saturating_mul(2u32.pow(exponent)).min(self.max_delay);
+        delay.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    // how long to wait before trying again after the attempt, None when the outcome stands
+    // a refused connection never reached the server, so it is retried whatever the method
+    pub fn delay(&self, method: Method, attempt: usize, elapsed: Duration, outcome: &Result<Response, Box<dyn Error>>) -> Option<Duration> {
+        if attempt >= self.attempts { return None }
+
+        let delay = match outcome {
+            Ok(response) if is_idempotent(method) && self.statuses.contains(&response.status.code()) => {
+                retry_after(&response.message.headers).unwrap_or_else(|| self.backoff(attempt))
+            }
+            Err(e) => match e.downcast_ref::<io::Error>().map(io::Error::kind) {
+                Some(ErrorKind::ConnectionRefused) => self.backoff(attempt),
+                Some(kind) if is_idempotent(method) && RESET.contains(&kind) => self.backoff(attempt),
+                _ => return None
+            },
+            _ => return None
+        };
+
+        // a delay too long to even add up (e.g. Retry-After: u64::MAX) is past any deadline
+        match elapsed.checked_add(delay) {
+            Some(total) if total <= self.timeout => Some(delay),
+            _ => None
+        }
+    }
+}
+
+// requests that can be sent twice without changing the outcome (rfc 7231 section 4.2.2)
+pub fn is_idempotent(method: Method) -> bool {
+    matches!(method, Method::GET | Method::HEAD)
+}
+
+// delay-seconds or an http-date, dates in the past mean right away
+pub fn retry_after(headers: &Headers) -> Option<Duration> {
+    let retry_after = headers.get("retry-after")?.trim();
+    match retry_after.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(retry_after).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use crate::http::*;
+
+    #[test]
+    fn backoff_and_retry_after() {
+        let policy = RetryPolicy::new();
+        for attempt in 1..40 {
+            let cap = policy.base.saturating_mul(2u32.pow(attempt.min(32) as u32 - 1)).min(policy.max_delay);
+            assert!(policy.backoff(attempt) <= cap);
+        }
+
+        let mut response = Response::new();
+        response.status = Status::ServiceUnavailable;
+        response.message.headers.add(Header::new("Retry-After", "2"));
+        let outcome = Ok(response);
+        assert_eq!(policy.delay(Method::GET, 1, Duration::from_secs(0), &outcome), Some(Duration::from_secs(2)));
+        assert_eq!(policy.delay(Method::GET, 1, Duration::from_secs(29), &outcome), None);
+        assert_eq!(policy.delay(Method::GET, 3, Duration::from_secs(0), &outcome), None);
+        assert_eq!(policy.delay(Method::POST, 1, Duration::from_secs(0), &outcome), None);
+
+        let mut response = Response::new();
+        response.status = Status::ServiceUnavailable;
+        response.message.headers.add(Header::new("Retry-After", u64::MAX.to_string()));
+        assert_eq!(policy.delay(Method::GET, 1, Duration::from_secs(1), &Ok(response)), None);
+
+        let past = SystemTime::now() - Duration::from_secs(60);
+        let mut headers = Headers::new();
+        headers.add(Header::new("Retry-After", httpdate::fmt_http_date(past)));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+
+        let reset: Result<Response, Box<dyn Error>> = Err(Box::new(io::Error::from(ErrorKind::ConnectionReset)));
+        assert!(policy.delay(Method::GET, 1, Duration::from_secs(0), &reset).is_some());
+        assert!(policy.delay(Method::POST, 1, Duration::from_secs(0), &reset).is_none());
+        let refused: Result<Response, Box<dyn Error>> = Err(Box::new(io::Error::from(ErrorKind::ConnectionRefused)));
+        assert!(policy.delay(Method::POST, 1, Duration::from_secs(0), &refused).is_some());
+    }
+
+    // listeners want a handler that lives as long as they do
+    fn leak<H: Fn(Request) -> Option<Response> + Sync>(handler: H) -> &'static H {
+        Box::leak(Box::new(handler))
+    }
+
+    fn is_eof(outcome: Result<Response, Box<dyn Error>>) -> bool {
+        outcome.err().and_then(|e| e.downcast_ref::<io::Error>().map(io::Error::kind)) == Some(ErrorKind::UnexpectedEof)
+    }
+
+    #[test]
+    fn retry_unavailable() {
+        // closes the connection without answering, then unavailable, then fine
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let flaky = leak(move |_: Request| {
+            let mut response = Response::new();
+            match counted.fetch_add(1, Ordering::SeqCst) % 3 {
+                0 => return None,
+                1 => {
+                    response.status = Status::ServiceUnavailable;
+                    response.message.headers.add(Header::new("Retry-After", "0"));
+                }
+                _ => ()
+            }
+            Some(response)
+        });
+        let (listener, port) = crate::tests::local();
+        Http11::listen_on(listener, flaky).unwrap();
+        let uri = format!("http://127.0.0.1:{}/", port);
+
+        let response = Http::get(&uri).unwrap();
+        assert!(matches!(response.status, Status::Ok));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        let mut client = Http::new();
+        client.retry = RetryPolicy::none();
+        assert!(is_eof(client.fetch(&uri)));
+        assert!(matches!(client.fetch(&uri).unwrap().status, Status::ServiceUnavailable));
+
+        // nobody listens on the port anymore
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as usize;
+        let mut client = Http::new();
+        client.retry.base = Duration::from_millis(20);
+        client.retry.timeout = Duration::from_millis(100);
+        assert!(client.fetch(&format!("http://127.0.0.1:{}/", port)).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{ self, Display, Formatter };
+use std::sync::Mutex;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+use aes_gcm::{ Aes256Gcm, KeyInit, Nonce };
+use aes_gcm::aead::{ self, Aead };
+use rand::Rng;
+use crate::message::*;
+use crate::cookie::*;
+
+// sessions kept in signed (and optionally encrypted) cookies, or in a store keyed by a signed id cookie
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const NONCE_LENGTH: usize = 12;
+const ID_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    id: Option<String>,
+    data: HashMap<String, String>,
+    changed: bool,
+    destroyed: bool,
+    // the cookie was signed with an older key and gets signed again with the current one
+    stale: bool
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the id in the store, None for cookie sessions and sessions that were never saved
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(String::as_str)
+    }
+
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.data.insert(key.into(), value.into());
+        self.changed = true;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let value = self.data.remove(key);
+        self.changed |= value.is_some();
+        value
+    }
+
+    // drops the data and tells the client to forget the cookie
+    pub fn destroy(&mut self) {
+        self.data.clear();
+        self.destroyed = true;
+    }
+
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+}
+
+// where sessions live when the cookie only carries their id, expired sessions must not be loaded
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Option<HashMap<String, String>>;
+    fn save(&self, id: &str, data: &HashMap<String, String>, expires: SystemTime);
+    fn remove(&self, id: &str);
+}
+
+type Stored = (HashMap<String, String>, SystemTime);
+
+#[derive(Default)]
+pub struct MemoryStore(Mutex<HashMap<String, Stored>>);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<HashMap<String, String>> {
+        let sessions = self.0.lock().unwrap();
+        sessions.get(id).filter(|(_, expires)| *expires > SystemTime::now()).map(|(data, _)| data.clone())
+    }
+
+    // expired sessions are swept out whenever one is saved
+    fn save(&self, id: &str, data: &HashMap<String, String>, expires: SystemTime) {
+        let mut sessions = self.0.lock().unwrap();
+        let now = SystemTime::now();
+        sessions.retain(|_, (_, expires)| *expires > now);
+        sessions.insert(id.to_string(), (data.clone(), expires));
+    }
+
+    fn remove(&self, id: &str) {
+        self.0.lock().unwrap().remove(id);
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Signature,
+    Decryption,
+    Expired,
+    Format
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for SessionError { }
+
+// the first key signs, every key verifies so old cookies survive a rotation
+pub struct Sessions {
+    // name and attributes of the session cookie, its value is ignored
+    pub cookie: Cookie,
+    pub max_age: Duration,
+    keys: Vec<Vec<u8>>,
+    encrypt: bool,
+    store: Option<Box<dyn SessionStore>>
+}
+
+impl Sessions {
+    pub fn new(keys: Vec<Vec<u8>>) -> Self {
+        assert!(!keys.is_empty(), "sessions need at least one key");
+        let mut cookie = Cookie::new("session", "");
+        cookie.path = Some("/".to_string());
+        cookie.http_only = true;
+        cookie.same_site = Some(SameSite::Lax);
+        Self { cookie, max_age: DEFAULT_MAX_AGE, keys, encrypt: false, store: None }
+    }
+
+    // the data can't be read by the client either, aes-256-gcm with a key derived from the signing key
+    pub fn encrypted(keys: Vec<Vec<u8>>) -> Self {
+        Self { encrypt: true, ..Sessions::new(keys) }
+    }
+
+    pub fn stored<S: SessionStore + 'static>(keys: Vec<Vec<u8>>, store: S) -> Self {
+        Self { store: Some(Box::new(store)), ..Sessions::new(keys) }
+    }
+
+    // sessions whose cookie is missing, tampered with or expired start out empty
+    pub fn load(&self, request: &Request) -> Session {
+        let mut session = Session::new();
+        if let Some(value) = request.cookie(&self.cookie.name) {
+            if let Ok((body, key)) = self.open(value) {
+                let loaded = match &self.store {
+                    Some(store) => String::from_utf8(body).ok().and_then(|id| Some((store.load(&id)?, Some(id)))),
+                    None => parse_data(&body).ok().map(|data| (data, None))
+                };
+                if let Some((data, id)) = loaded {
+                    session.data = data;
+                    session.id = id;
+                    session.stale = key > 0;
+                }
+            }
+        }
+
+        session
+    }
+
+    // sets the cookie when the session changed, a new expiry comes with it
+    pub fn save(&self, session: &Session, response: &mut Response) {
+        if session.destroyed {
+            if let (Some(store), Some(id)) = (&self.store, &session.id) {
+                store.remove(id);
+            }
+            let mut removal = Cookie::removal(self.cookie.name.clone());
+            removal.domain = self.cookie.domain.clone();
+            removal.path = self.cookie.path.clone();
+            response.set_cookie(&removal);
+            return
+        }
+        if !session.changed && !session.stale { return }
+
+        let expires = SystemTime::now() + self.max_age;
+        let body = match &self.store {
+            Some(store) => {
+                let id = session.id.clone().unwrap_or_else(new_id);
+                store.save(&id, &session.data, expires);
+                id.into_bytes()
+            }
+            None => construct_data(&session.data, expires)
+        };
+
+        let mut cookie = self.cookie.clone();
+        cookie.value = self.seal(&body);
+        cookie.max_age = Some(self.max_age);
+        response.set_cookie(&cookie);
+    }
+
+    // loads the session for the handler and saves it into whatever response comes back
+    pub fn handle<'r, 'a, R, F>(&self, request: R, handler: F) -> Option<Response<'a>>
+    where R: Borrow<Request<'r>>, F: FnOnce(R, &mut Session) -> Option<Response<'a>> {
+        let mut session = self.load(request.borrow());
+        let mut response = handler(request, &mut session)?;
+        self.save(&session, &mut response);
+        Some(response)
+    }
+
+    // base64url(body).base64url(hmac), the mac covers the cookie name so values can't be moved between cookies
+    fn seal(&self, body: &[u8]) -> String {
+        let key = &self.keys[0];
+        let body = if self.encrypt { encrypt(key, self.cookie.name.as_bytes(), body) } else { body.to_vec() };
+        let body = base64::encode_config(body, base64::URL_SAFE_NO_PAD);
+        let tag = sign(key, &self.cookie.name, &body).finalize().into_bytes();
+        format!("{}.{}", body, base64::encode_config(tag, base64::URL_SAFE_NO_PAD))
+    }
+
+    // the body and the index of the key that verified it
+    fn open(&self, value: &str) -> Result<(Vec<u8>, usize), Box<dyn Error>> {
+        let (body, tag) = value.split_once('.').ok_or(SessionError::Format)?;
+        let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD)?;
+        let (index, key) = self.keys.iter().enumerate()
+            .find(|(_, key)| sign(key, &self.cookie.name, body).verify_slice(&tag).is_ok())
+            .ok_or(SessionError::Signature)?;
+
+        let body = base64::decode_config(body, base64::URL_SAFE_NO_PAD)?;
+        let body = if self.encrypt { decrypt(key, self.cookie.name.as_bytes(), &body)? } else { body };
+        Ok((body, index))
+    }
+}
+
+fn sign(key: &[u8], name: &str, body: &str) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(name.as_bytes());
+    mac.update(b"=");
+    mac.update(body.as_bytes());
+    mac
+}
+
+fn cipher(key: &[u8]) -> Aes256Gcm {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("hmac takes keys of any length");
+    mac.update(b"session encryption");
+    Aes256Gcm::new(&mac.finalize().into_bytes())
+}
+
+// the random nonce goes in front of the ciphertext
+fn encrypt(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let nonce: [u8; NONCE_LENGTH] = rand::thread_rng().gen();
+    let ciphertext = cipher(key).encrypt(Nonce::from_slice(&nonce), aead::Payload { msg: plaintext, aad })
+        .expect("aes-gcm encrypts anything a cookie can hold");
+    let mut body = nonce.to_vec();
+    body.extend(ciphertext);
+    body
+}
+
+fn decrypt(key: &[u8], aad: &[u8], body: &[u8]) -> Result<Vec<u8>, SessionError> {
+    if body.len() < NONCE_LENGTH { Err(SessionError::Format)? }
+    let (nonce, ciphertext) = body.split_at(NONCE_LENGTH);
+    cipher(key).decrypt(Nonce::from_slice(nonce), aead::Payload { msg: ciphertext, aad }).map_err(|_| SessionError::Decryption)
+}
+
+fn new_id() -> String {
+    let id: [u8; ID_LENGTH] = rand::thread_rng().gen();
+    base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+}
+
+// expiry in unix seconds, then the pairs like a query string
+fn construct_data(data: &HashMap<String, String>, expires: SystemTime) -> Vec<u8> {
+    let expires = expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let pairs: Vec<_> = data.iter().map(|(key, value)| format!("{}={}", escape(key), escape(value))).collect();
+    format!("{}|{}", expires, pairs.join("&")).into_bytes()
+}
+
+fn parse_data(body: &[u8]) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let (expires, pairs) = std::str::from_utf8(body)?.split_once('|').ok_or(SessionError::Format)?;
+    if UNIX_EPOCH + Duration::from_secs(expires.parse()?) <= SystemTime::now() { Err(SessionError::Expired)? }
+
+    let mut data = HashMap::new();
+    for pair in pairs.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').ok_or(SessionError::Format)?;
+        data.insert(unescape(key)?, unescape(value)?);
+    }
+    Ok(data)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('%', "%25").replace('&', "%26").replace('=', "%3D")
+}
+
+fn unescape(text: &str) -> Result<String, SessionError> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok()).ok_or(SessionError::Format)?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| SessionError::Format)?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| SessionError::Format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::def::Header;
+
+    // the cookies a response sets, sent back like a browser would
+    fn carry(response: &Response) -> Request<'static> {
+        let pairs: Vec<_> = response.message.headers.get_all("set-cookie")
+            .filter_map(|set_cookie| set_cookie.split(';').next().map(str::to_string))
+            .collect();
+        let mut request = Request::new();
+        request.message.headers.add(Header::new("Cookie", pairs.join("; ")));
+        request
+    }
+
+    fn visit(_: &Request, session: &mut Session) -> Option<Response<'static>> {
+        let visits = session.get("visits").map_or(0, |visits| visits.parse::<usize>().unwrap()) + 1;
+        session.insert("visits", visits.to_string());
+        session.insert("note", "a=b&c%d");
+        Some(Response::new())
+    }
+
+    #[test]
+    fn signed_cookies() {
+        let sessions = Sessions::new(vec![b"old secret".to_vec()]);
+        let response = sessions.handle(&Request::new(), visit).unwrap();
+        let value = response.message.headers.get("set-cookie").unwrap().to_string();
+        assert!(value.starts_with("session=") && value.contains("; Max-Age=86400; Path=/; HttpOnly; SameSite=Lax"));
+
+        let request = carry(&response);
+        let session = sessions.load(&request);
+        assert_eq!(session.get("visits"), Some("1"));
+        assert_eq!(session.get("note"), Some("a=b&c%d"));
+
+        // an unchanged session sends no cookie
+        let response = sessions.handle(&request, |_, _| Some(Response::new())).unwrap();
+        assert!(response.message.headers.get("set-cookie").is_none());
+
+        // a rotated key still verifies and the cookie is signed again with the new one
+        let rotated = Sessions::new(vec![b"new secret".to_vec(), b"old secret".to_vec()]);
+        let response = rotated.handle(&request, |_, _| Some(Response::new())).unwrap();
+        let request = carry(&response);
+        assert_eq!(Sessions::new(vec![b"new secret".to_vec()]).load(&request).get("visits"), Some("1"));
+
+        // any change to the value breaks the signature
+        let tampered = request.message.headers.get("cookie").unwrap().replacen("session=", "session=x", 1);
+        let mut request = Request::new();
+        request.message.headers.add(Header::new("Cookie", tampered));
+        assert!(rotated.load(&request).get("visits").is_none());
+    }
+
+    #[test]
+    fn encrypted_cookies_expire() {
+        let mut sessions = Sessions::encrypted(vec![b"secret".to_vec()]);
+        let response = sessions.handle(&Request::new(), visit).unwrap();
+        let request = carry(&response);
+        assert!(!request.message.headers.get("cookie").unwrap().contains("visits"));
+        assert_eq!(sessions.load(&request).get("visits"), Some("1"));
+        assert!(Sessions::new(vec![b"secret".to_vec()]).load(&request).get("visits").is_none());
+
+        sessions.max_age = Duration::from_secs(0);
+        let request = carry(&sessions.handle(&Request::new(), visit).unwrap());
+        assert!(sessions.load(&request).get("visits").is_none());
+    }
+
+    #[test]
+    fn stored_sessions() {
+        let sessions = Sessions::stored(vec![b"secret".to_vec()], MemoryStore::new());
+        let request = carry(&sessions.handle(&Request::new(), visit).unwrap());
+        assert!(!request.message.headers.get("cookie").unwrap().contains("visits"));
+
+        let response = sessions.handle(&request, visit).unwrap();
+        // the id stays the same, so the cookie doesn't change
+        assert_eq!(carry(&response).message.headers.get("cookie"), request.message.headers.get("cookie"));
+        let session = sessions.load(&request);
+        assert_eq!(session.get("visits"), Some("2"));
+        assert!(session.id().is_some());
+
+        let response = sessions.handle(&request, |_, session| {
+            session.destroy();
+            Some(Response::new())
+        }).unwrap();
+        assert!(response.message.headers.get("set-cookie").unwrap().contains("Max-Age=0"));
+        assert!(sessions.load(&request).get("visits").is_none());
+    }
+}
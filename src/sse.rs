@@ -0,0 +1,291 @@
+use std::error::Error;
+use std::io::{ self, Read, Write, BufRead, BufReader };
+use std::sync::mpsc::{ self, Sender, Receiver, RecvTimeoutError };
+use std::thread;
+use std::time::Duration;
+use crate::def::*;
+use crate::message::*;
+use crate::http::*;
+
+// server-sent events (the text/event-stream format of the html living standard)
+
+// how long a client waits before reconnecting, until the server sends a retry
+pub const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+const KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<Duration>
+}
+
+impl Event {
+    pub fn new<S: Into<String>>(data: S) -> Self {
+        Self { data: data.into(), ..Self::default() }
+    }
+
+    // every line of the data becomes its own data field, newlines in id and event would break the stream
+    pub fn construct(&self) -> Vec<u8> {
+        let mut event = String::new();
+        if let Some(id) = &self.id {
+            event.push_str(&format!("id: {}\n", id.replace(['\r', '\n'], "")));
+        }
+        if let Some(name) = &self.event {
+            event.push_str(&format!("event: {}\n", name.replace(['\r', '\n'], "")));
+        }
+        if let Some(retry) = self.retry {
+            event.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n') {
+            event.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        event.push('\n');
+        event.into_bytes()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.construct())
+    }
+}
+
+// a text/event-stream response, the events sent through the sender are written as they come in and a comment
+// keeps the connection alive whenever nothing was sent for keep_alive, the stream ends once every sender is dropped
+pub fn event_stream(keep_alive: Duration) -> (Response<'static>, Sender<Event>) {
+    let (sender, receiver) = mpsc::channel::<Event>();
+
+    let mut response = Response::new();
+    response.message.headers.add(Header::from(ContentType::EventStream));
+    response.message.headers.add(Header::new("Cache-Control", "no-cache"));
+    response.stream(Events { receiver, keep_alive, pending: Vec::new() });
+
+    (response, sender)
+}
+
+// the payload of an event stream, a read blocks until the next event or keep-alive comment
+struct Events {
+    receiver: Receiver<Event>,
+    keep_alive: Duration,
+    // what is left of the last event after a short read
+    pending: Vec<u8>
+}
+
+impl Read for Events {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            self.pending = match self.receiver.recv_timeout(self.keep_alive) {
+                Ok(event) => event.construct(),
+                Err(RecvTimeoutError::Timeout) => KEEP_ALIVE.to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0)
+            };
+        }
+
+        let size = buf.len().min(self.pending.len());
+        buf[..size].copy_from_slice(&self.pending[..size]);
+        self.pending.drain(..size);
+        Ok(size)
+    }
+}
+
+// reads the events of a text/event-stream resource, reconnecting with Last-Event-ID when the stream ends
+// errors are handed out by the iterator, it only stops for good once the server answers with something
+// other than an event stream
+pub struct EventSource {
+    address: Address<'static>,
+    protocol: Protocol,
+    request: Request<'static>,
+    body: Option<BufReader<Body<'static>>>,
+    last_event_id: Option<String>,
+    retry: Duration,
+    closed: bool
+}
+
+impl EventSource {
+    pub fn new(address: Address<'static>, protocol: Protocol, request: Request<'static>) -> Self {
+        Self { address, protocol, request, body: None, last_event_id: None, retry: DEFAULT_RETRY, closed: false }
+    }
+
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    fn connect(&mut self) -> Result<BufReader<Body<'static>>, Box<dyn Error>> {
+        let mut request = self.request.clone();
+        request.message.headers.add(Header::new("Accept", ContentType::EventStream.value()));
+        request.message.headers.add(Header::new("Cache-Control", "no-cache"));
+        if let Some(id) = &self.last_event_id {
+            request.message.headers.add(Header::new("Last-Event-ID", id.clone()));
+        }
+
+        let (response, body) = match self.protocol {
+            Protocol::Http => Http11::stream(self.address.clone(), &mut request)?,
+            Protocol::Https => Https11::new(self.address.clone())?.into_stream(&mut request)?
+        };
+
+        let content_type = response.message.headers.get(ContentType::normalized()).and_then(|t| t.split(';').next());
+        if !matches!(response.status, Status::Ok) || !matches!(content_type.and_then(|t| ContentType::parse(t.trim())), Some(ContentType::EventStream)) {
+            self.closed = true;
+            Err(ParsingError::ContentType)?
+        }
+
+        Ok(BufReader::new(body))
+    }
+
+    // None when the stream ended before the next event
+    fn read_event(&mut self) -> Result<Option<Event>, Box<dyn Error>> {
+        let body = match self.body.as_mut() {
+            Some(body) => body,
+            None => return Ok(None)
+        };
+
+        let mut event = Event::default();
+        let mut data = None::<String>;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if body.read_line(&mut line)? == 0 { return Ok(None) }
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+
+            // a blank line dispatches the event, unless it has no data
+            if line.is_empty() {
+                match data.take() {
+                    Some(mut data) => {
+                        // the newline after the last data line isn't part of it
+                        data.pop();
+                        event.data = data;
+                        event.id = self.last_event_id.clone();
+                        return Ok(Some(event))
+                    }
+                    None => {
+                        event = Event::default();
+                        continue
+                    }
+                }
+            }
+            if line.starts_with(':') { continue }
+
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, "")
+            };
+            match field {
+                "data" => {
+                    let data = data.get_or_insert_with(String::new);
+                    data.push_str(value);
+                    data.push('\n');
+                }
+                "event" => event.event = Some(value.to_string()),
+                "id" if !value.contains('\0') => self.last_event_id = Some(value.to_string()),
+                // ascii digits only, parse would take a sign as well
+                "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => if let Ok(retry) = value.parse::<u64>() {
+                    self.retry = Duration::from_millis(retry);
+                    event.retry = Some(self.retry);
+                },
+                _ => ()
+            }
+        }
+    }
+}
+
+impl Iterator for EventSource {
+    type Item = Result<Event, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.closed { return None }
+
+            if self.body.is_none() {
+                match self.connect() {
+                    Ok(body) => self.body = Some(body),
+                    Err(e) => {
+                        if !self.closed { thread::sleep(self.retry); }
+                        return Some(Err(e))
+                    }
+                }
+            }
+
+            match self.read_event() {
+                Ok(Some(event)) => return Some(Ok(event)),
+                Ok(None) => {
+                    self.body = None;
+                    thread::sleep(self.retry);
+                }
+                Err(e) => {
+                    self.body = None;
+                    thread::sleep(self.retry);
+                    return Some(Err(e))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(request: Request) -> Option<Response> {
+        let (response, sender) = event_stream(Duration::from_millis(10));
+        let resumed = request.message.headers.get("last-event-id").map(str::to_string);
+        thread::spawn(move || match resumed {
+            None => {
+                let mut first = Event::new("first\nline");
+                first.id = Some("1".to_string());
+                first.event = Some("greeting".to_string());
+                first.retry = Some(Duration::from_millis(10));
+                sender.send(first).unwrap();
+                // keep-alive comments go out in the meantime
+                thread::sleep(Duration::from_millis(50));
+                let mut second = Event::new("second");
+                second.id = Some("2".to_string());
+                sender.send(second).unwrap();
+            }
+            Some(id) => sender.send(Event::new(format!("resumed after {}", id))).unwrap()
+        });
+        Some(response)
+    }
+
+    #[test]
+    fn reconnect_with_last_event_id() {
+        let (listener, port) = crate::tests::local();
+        Http11::listen_on(listener, &counter).unwrap();
+
+        let mut events = EventSource::new(Address::new("127.0.0.1", Some(port)), Protocol::Http, Request::new());
+        let first = events.next().unwrap().unwrap();
+        assert_eq!(first.data, "first\nline");
+        assert_eq!(first.event.as_deref(), Some("greeting"));
+        assert_eq!(first.retry, Some(Duration::from_millis(10)));
+        assert_eq!(events.next().unwrap().unwrap().id.as_deref(), Some("2"));
+
+        // the first stream ended, the event comes from a second connection
+        let resumed = events.next().unwrap().unwrap();
+        assert_eq!(resumed.data, "resumed after 2");
+        assert_eq!(resumed.id.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn retry_digits_only() {
+        let mut events = EventSource::new(Address::new("127.0.0.1", None), Protocol::Http, Request::new());
+        let raw = b"retry: +5\ndata: signed\n\nretry: 7\ndata: digits\n\n".to_vec();
+        events.body = Some(BufReader::new(Body::response(io::Cursor::new(raw), &Response::new(), Method::GET).unwrap()));
+
+        let signed = events.read_event().unwrap().unwrap();
+        assert_eq!((signed.retry, events.retry), (None, DEFAULT_RETRY));
+        assert_eq!(events.read_event().unwrap().unwrap().retry, Some(Duration::from_millis(7)));
+    }
+
+    #[test]
+    fn stream_over_h2() {
+        let (listener, port) = crate::tests::local();
+        crate::h2::Http2::listen_on(listener, &counter).unwrap();
+
+        let mut client = crate::h2::Http2::prior_knowledge(Address::new("127.0.0.1", Some(port))).unwrap();
+        let response = client.send(&mut Request::new()).unwrap();
+        assert_eq!(response.message.headers.get("content-type"), Some(ContentType::EventStream.value()));
+        let text = response.message.payload.text();
+        assert!(text.starts_with("id: 1\nevent: greeting\nretry: 10\ndata: first\ndata: line\n\n"));
+        assert!(text.contains(": keep-alive\n\n"));
+        assert!(text.ends_with("id: 2\ndata: second\n\n"));
+    }
+}
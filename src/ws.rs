@@ -0,0 +1,767 @@
+use std::error::Error;
+use std::fmt::{ self, Display, Formatter };
+use std::io::{ self, Read, Write, BufReader };
+use std::net::TcpStream;
+use flate2::{ Compress, Compression, Decompress, FlushCompress, FlushDecompress };
+use crate::def::*;
+use crate::message::*;
+use crate::http::*;
+
+// websockets (rfc 6455) on top of any blocking stream, the handshake is a regular http/1.1 upgrade
+
+pub const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const UPGRADE_WEBSOCKET: &str = "websocket";
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+const VERSION: &str = "13";
+
+// the largest message we take before closing with TooBig, fragments included
+pub const MAX_MESSAGE_SIZE: usize = 1 << 24;
+const MAX_CONTROL_SIZE: usize = 125;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong
+}
+
+impl Opcode {
+    pub fn parse(code: u8) -> Option<Self> {
+        match code {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xa => Some(Self::Pong),
+            _ => None
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xa
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseCode {
+    Normal,
+    GoingAway,
+    ProtocolError,
+    Unsupported,
+    NoStatus,
+    Abnormal,
+    InvalidData,
+    PolicyViolation,
+    TooBig,
+    MandatoryExtension,
+    InternalError,
+    Other(u16)
+}
+
+impl CloseCode {
+    pub fn parse(code: u16) -> Self {
+        match code {
+            1000 => Self::Normal,
+            1001 => Self::GoingAway,
+            1002 => Self::ProtocolError,
+            1003 => Self::Unsupported,
+            1005 => Self::NoStatus,
+            1006 => Self::Abnormal,
+            1007 => Self::InvalidData,
+            1008 => Self::PolicyViolation,
+            1009 => Self::TooBig,
+            1010 => Self::MandatoryExtension,
+            1011 => Self::InternalError,
+            code => Self::Other(code)
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Normal => 1000,
+            Self::GoingAway => 1001,
+            Self::ProtocolError => 1002,
+            Self::Unsupported => 1003,
+            Self::NoStatus => 1005,
+            Self::Abnormal => 1006,
+            Self::InvalidData => 1007,
+            Self::PolicyViolation => 1008,
+            Self::TooBig => 1009,
+            Self::MandatoryExtension => 1010,
+            Self::InternalError => 1011,
+            Self::Other(code) => *code
+        }
+    }
+
+    // NoStatus and Abnormal only exist locally, they must never be sent
+    fn is_sendable(&self) -> bool {
+        match self.code() {
+            1005 | 1006 | 1015 => false,
+            code => (1000..5000).contains(&code) && !(1016..3000).contains(&code) && code != 1004
+        }
+    }
+}
+
+impl Display for CloseCode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CloseCode { }
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Status,
+    Upgrade,
+    Accept,
+    Protocol,
+    Extension,
+    Closed
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for HandshakeError { }
+
+pub struct Frame {
+    pub fin: bool,
+    // set on compressed messages once an extension negotiated it
+    pub rsv1: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Self {
+        Self { fin: true, rsv1: false, opcode, payload }
+    }
+
+    // the payload comes back unmasked, masked tells whether the sender masked it
+    pub fn read<R: Read>(reader: &mut R, max_size: usize) -> Result<(Self, bool), Box<dyn Error>> {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+        if head[0] & 0x30 != 0 { Err(CloseCode::ProtocolError)? }
+        let opcode = Opcode::parse(head[0] & 0x0f).ok_or(CloseCode::ProtocolError)?;
+        let fin = head[0] & 0x80 != 0;
+        let masked = head[1] & 0x80 != 0;
+
+        let length = match head[1] & 0x7f {
+            126 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                reader.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64
+        };
+        if opcode.is_control() && (!fin || length > MAX_CONTROL_SIZE as u64) { Err(CloseCode::ProtocolError)? }
+        if length > max_size as u64 { Err(CloseCode::TooBig)? }
+
+        let mut mask = [0; 4];
+        if masked { reader.read_exact(&mut mask)?; }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        if masked { apply_mask(&mut payload, mask); }
+
+        Ok((Self { fin, rsv1: head[0] & 0x40 != 0, opcode, payload }, masked))
+    }
+
+    pub fn construct(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.payload.len() + 14);
+        frame.push((self.fin as u8) << 7 | (self.rsv1 as u8) << 6 | self.opcode.code());
+
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            length if length < 126 => frame.push(mask_bit | length as u8),
+            length if length <= u16::MAX as usize => {
+                frame.push(mask_bit | 126);
+                frame.extend(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(mask_bit | 127);
+                frame.extend(&(length as u64).to_be_bytes());
+            }
+        }
+
+        match mask {
+            Some(mask) => {
+                frame.extend(&mask);
+                let start = frame.len();
+                frame.extend(&self.payload);
+                apply_mask(&mut frame[start..], mask);
+            }
+            None => frame.extend(&self.payload)
+        }
+
+        frame
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W, mask: Option<[u8; 4]>) -> io::Result<()> {
+        writer.write_all(&self.construct(mask))
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(CloseCode, String)>)
+}
+
+// the value of Sec-WebSocket-Accept for a Sec-WebSocket-Key
+pub fn accept(key: &str) -> String {
+    let digest = sha1::Sha1::from(format!("{}{}", key.trim(), GUID)).digest().bytes();
+    base64::encode(digest)
+}
+
+// whether a comma separated header like Connection or Upgrade has the token
+pub(crate) fn has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    headers.get(name).is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+type Parameters = Vec<(String, Option<String>)>;
+
+// the extensions of a Sec-WebSocket-Extensions header, each with its parameters
+fn extensions(value: &str) -> Vec<(String, Parameters)> {
+    value.split(',').filter_map(|extension| {
+        let mut parts = extension.split(';').map(str::trim);
+        let name = parts.next().filter(|name| !name.is_empty())?.to_lowercase();
+        let parameters = parts.filter(|p| !p.is_empty()).map(|parameter| match parameter.split_once('=') {
+            Some((key, value)) => (key.trim().to_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+            None => (parameter.to_lowercase(), None)
+        }).collect();
+        Some((name, parameters))
+    }).collect()
+}
+
+// picks the first permessage-deflate offer we can take (rfc 7692), with the answer for the client
+// our compressor always uses the full window, so offers limiting it are passed over
+fn negotiate_deflate(offers: &str) -> Option<(String, Parameters)> {
+    'offers: for (name, parameters) in extensions(offers) {
+        if name != PERMESSAGE_DEFLATE { continue }
+        let mut answer = PERMESSAGE_DEFLATE.to_string();
+        for (key, value) in parameters.iter() {
+            match (key.as_str(), value.as_deref()) {
+                ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => {
+                    answer.push_str("; ");
+                    answer.push_str(key);
+                }
+                ("server_max_window_bits", Some("15")) => answer.push_str("; server_max_window_bits=15"),
+                // a smaller client window still inflates with the full one
+                ("client_max_window_bits", _) => (),
+                _ => continue 'offers
+            }
+        }
+        return Some((answer, parameters))
+    }
+
+    None
+}
+
+// per message compression state, each end may have to start from an empty window for every message
+struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    reset_compress: bool,
+    reset_decompress: bool
+}
+
+// every message is compressed as if it ended with a sync flush, which isn't sent
+const DEFLATE_TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+impl Deflate {
+    fn new(client: bool, parameters: &[(String, Option<String>)]) -> Self {
+        let has = |key: &str| parameters.iter().any(|(k, _)| k == key);
+        let (own, peer) = if client { ("client_no_context_takeover", "server_no_context_takeover") }
+            else { ("server_no_context_takeover", "client_no_context_takeover") };
+
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            reset_compress: has(own),
+            reset_decompress: has(peer)
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)?;
+            // the flush is complete once everything is in and there was room to spare
+            if (self.compress.total_in() - start) as usize == data.len() && output.len() < output.capacity() { break }
+            output.reserve(output.capacity());
+        }
+
+        if output.ends_with(&DEFLATE_TAIL) { output.truncate(output.len() - DEFLATE_TAIL.len()); }
+        if self.reset_compress { self.compress.reset(); }
+        Ok(output)
+    }
+
+    fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut input = data.to_vec();
+        input.extend(&DEFLATE_TAIL);
+        let mut output = Vec::with_capacity(data.len() * 4 + 64);
+        let start = self.decompress.total_in();
+        loop {
+            let (consumed, produced) = (self.decompress.total_in() - start, output.len());
+            let status = self.decompress.decompress_vec(&input[consumed as usize..], &mut output, FlushDecompress::Sync)
+                .or(Err(CloseCode::InvalidData))?;
+            if output.len() > max_size { Err(CloseCode::TooBig)? }
+
+            let done = (self.decompress.total_in() - start) as usize == input.len();
+            if status == flate2::Status::StreamEnd || (done && output.len() < output.capacity()) { break }
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            } else if self.decompress.total_in() - start == consumed && output.len() == produced {
+                Err(CloseCode::InvalidData)?
+            }
+        }
+
+        if self.reset_decompress { self.decompress.reset(false); }
+        Ok(output)
+    }
+}
+
+// answers a websocket upgrade, the handler gets the socket once the 101 response is written
+// protocols are the subprotocols we speak by preference, our first one the client also offered wins
+// None when the request isn't a websocket upgrade, so the handler can answer it some other way
+pub fn upgrade<F>(request: &Request, protocols: &[&str], deflate: bool, handler: F) -> Option<Response<'static>>
+where F: FnOnce(WebSocket<Upgraded>) + Send + 'static {
+    let headers = &request.message.headers;
+    if !matches!(request.method, Method::GET) { return None }
+    if !has_token(headers, "upgrade", UPGRADE_WEBSOCKET) || !has_token(headers, "connection", "upgrade") { return None }
+    if headers.get("sec-websocket-version").map(str::trim) != Some(VERSION) { return None }
+    let key = headers.get("sec-websocket-key")?;
+    let offered = headers.get("sec-websocket-protocol").unwrap_or("");
+    let protocol = protocols.iter().find(|p| offered.split(',').any(|o| o.trim() == **p)).map(|p| p.to_string());
+    let extension = headers.get("sec-websocket-extensions").filter(|_| deflate).and_then(negotiate_deflate);
+    let (answer, parameters) = match extension {
+        Some((answer, parameters)) => (Some(answer), Some(parameters)),
+        None => (None, None)
+    };
+
+    let selected = protocol.clone();
+    let mut response = Response::switching_protocols(UPGRADE_WEBSOCKET, move |upgraded| {
+        let mut socket = WebSocket::new(BufReader::new(upgraded), false);
+        socket.protocol = selected;
+        socket.deflate = parameters.map(|parameters| Deflate::new(false, &parameters));
+        handler(socket)
+    });
+    response.message.headers.add(Header::new("Sec-WebSocket-Accept", accept(key)));
+    if let Some(protocol) = protocol {
+        response.message.headers.add(Header::new("Sec-WebSocket-Protocol", protocol));
+    }
+    if let Some(answer) = answer {
+        response.message.headers.add(Header::new("Sec-WebSocket-Extensions", answer));
+    }
+    Some(response)
+}
+
+pub struct WebSocket<S: Read + Write> {
+    stream: BufReader<S>,
+    // clients mask everything they send, servers nothing
+    client: bool,
+    // messages longer than this are split into continuation frames
+    fragment_size: Option<usize>,
+    max_size: usize,
+    // the opcode and data of a fragmented message still coming in, and whether it's compressed
+    fragments: Option<(Opcode, bool, Vec<u8>)>,
+    protocol: Option<String>,
+    deflate: Option<Deflate>,
+    close_sent: bool,
+    close_received: bool
+}
+
+pub type Wss<'a> = WebSocket<TlsStream<'a>>;
+
+impl WebSocket<TcpStream> {
+    pub fn connect(address: Address, request: &mut Request) -> Result<(Self, Response<'static>), Box<dyn Error>> {
+        let stream = address.connect()?;
+        WebSocket::handshake(stream, address.host(), request)
+    }
+}
+
+impl<'a> WebSocket<TlsStream<'a>> {
+    // alpn is limited to http/1.1, upgrades don't exist on http/2 connections
+    pub fn connect_tls(address: Address<'a>, request: &mut Request) -> Result<(Self, Response<'static>), Box<dyn Error>> {
+        let host = address.host();
+        let stream = TlsStream::connect_with(address, &RUSTLS_H11_CLIENT_CONFIG)?;
+        WebSocket::handshake(stream, host, request)
+    }
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    // sends the upgrade request and checks the server's answer, the request may carry extra headers
+    // like Sec-WebSocket-Protocol or a permessage-deflate offer in Sec-WebSocket-Extensions
+    pub fn handshake(mut stream: S, host: String, request: &mut Request) -> Result<(Self, Response<'static>), Box<dyn Error>> {
+        let key = base64::encode(rand::random::<[u8; 16]>());
+        request.method = Method::GET;
+        request.version = Version::V11;
+        request.message.headers.add(Header::new("Host", host));
+        request.message.headers.add(Header::new("Connection", "Upgrade"));
+        request.message.headers.add(Header::new("Upgrade", UPGRADE_WEBSOCKET));
+        request.message.headers.add(Header::new("Sec-WebSocket-Version", VERSION));
+        request.message.headers.add(Header::new("Sec-WebSocket-Key", key.clone()));
+        request.write_to(&mut stream)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut text = String::new();
+        let response = Response::parse_head(&mut reader, &mut text)?.into_owned();
+        let headers = &response.message.headers;
+        if !matches!(response.status, Status::SwitchingProtocols) { Err(HandshakeError::Status)? }
+        if !has_token(headers, "upgrade", UPGRADE_WEBSOCKET) || !has_token(headers, "connection", "upgrade") {
+            Err(HandshakeError::Upgrade)?
+        }
+        if headers.get("sec-websocket-accept").map(str::trim) != Some(accept(&key).as_str()) {
+            Err(HandshakeError::Accept)?
+        }
+
+        let mut socket = WebSocket::new(reader, true);
+        if let Some(protocol) = headers.get("sec-websocket-protocol").map(str::trim) {
+            let offered = request.message.headers.get("sec-websocket-protocol").unwrap_or("");
+            if !offered.split(',').any(|o| o.trim() == protocol) { Err(HandshakeError::Protocol)? }
+            socket.protocol = Some(protocol.to_string());
+        }
+        if let Some(answer) = headers.get("sec-websocket-extensions") {
+            let offered = request.message.headers.get("sec-websocket-extensions").unwrap_or("");
+            let offered = extensions(offered).iter().any(|(name, _)| name == PERMESSAGE_DEFLATE);
+            for (name, parameters) in extensions(answer) {
+                // we can't compress with a smaller window than the default
+                let window = parameters.iter().any(|(k, v)| k == "client_max_window_bits" && v.as_deref() != Some("15"));
+                if !offered || name != PERMESSAGE_DEFLATE || socket.deflate.is_some() || window {
+                    Err(HandshakeError::Extension)?
+                }
+                socket.deflate = Some(Deflate::new(true, &parameters));
+            }
+        }
+
+        Ok((socket, response))
+    }
+
+    // a stream the handshake already happened on, anything read past it has to be in the reader's buffer
+    pub fn new(stream: BufReader<S>, client: bool) -> Self {
+        Self {
+            stream,
+            client,
+            fragment_size: None,
+            max_size: MAX_MESSAGE_SIZE,
+            fragments: None,
+            protocol: None,
+            deflate: None,
+            close_sent: false,
+            close_received: false
+        }
+    }
+
+    pub fn set_fragment_size(&mut self, size: Option<usize>) {
+        self.fragment_size = size.filter(|size| *size > 0);
+    }
+
+    pub fn set_max_size(&mut self, size: usize) {
+        self.max_size = size;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+
+    // the subprotocol agreed on in the handshake
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.deflate.is_some()
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        self.send(WsMessage::Text(text.to_string()))
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.send(WsMessage::Binary(data.to_vec()))
+    }
+
+    pub fn ping(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.send(WsMessage::Ping(data.to_vec()))
+    }
+
+    pub fn send(&mut self, message: WsMessage) -> Result<(), Box<dyn Error>> {
+        if self.close_sent { Err(HandshakeError::Closed)? }
+
+        let (opcode, payload) = match message {
+            WsMessage::Text(text) => (Opcode::Text, text.into_bytes()),
+            WsMessage::Binary(data) => (Opcode::Binary, data),
+            WsMessage::Ping(data) => (Opcode::Ping, data),
+            WsMessage::Pong(data) => (Opcode::Pong, data),
+            WsMessage::Close(reason) => {
+                let (code, reason) = reason.unwrap_or((CloseCode::Normal, String::new()));
+                return self.close(code, &reason)
+            }
+        };
+        if opcode.is_control() && payload.len() > MAX_CONTROL_SIZE { Err(CloseCode::TooBig)? }
+
+        // only the first frame of a compressed message is marked
+        let (payload, compressed) = match self.deflate.as_mut() {
+            Some(deflate) if !opcode.is_control() => (deflate.compress(&payload)?, true),
+            _ => (payload, false)
+        };
+
+        match self.fragment_size {
+            Some(size) if !opcode.is_control() && payload.len() > size => {
+                let mut chunks = payload.chunks(size).peekable();
+                let mut opcode = opcode;
+                while let Some(chunk) = chunks.next() {
+                    let mut frame = Frame::new(opcode, chunk.to_vec());
+                    frame.fin = chunks.peek().is_none();
+                    frame.rsv1 = compressed && opcode != Opcode::Continuation;
+                    self.write(&frame)?;
+                    opcode = Opcode::Continuation;
+                }
+            }
+            _ => {
+                let mut frame = Frame::new(opcode, payload);
+                frame.rsv1 = compressed;
+                self.write(&frame)?
+            }
+        }
+
+        self.stream.get_mut().flush()?;
+        Ok(())
+    }
+
+    fn write(&mut self, frame: &Frame) -> io::Result<()> {
+        let mask = if self.client { Some(rand::random()) } else { None };
+        frame.write_to(self.stream.get_mut(), mask)
+    }
+
+    // starts the closing handshake and waits for the peer's close, messages still coming in are dropped
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), Box<dyn Error>> {
+        if !self.close_sent {
+            self.send_close(code, reason)?;
+        }
+        while !self.close_received {
+            self.receive()?;
+        }
+
+        Ok(())
+    }
+
+    fn send_close(&mut self, code: CloseCode, reason: &str) -> Result<(), Box<dyn Error>> {
+        let mut payload = Vec::new();
+        if code.is_sendable() {
+            payload.extend(&code.code().to_be_bytes());
+            payload.extend(reason.as_bytes());
+            payload.truncate(MAX_CONTROL_SIZE);
+        }
+        self.close_sent = true;
+        self.write(&Frame::new(Opcode::Close, payload))?;
+        self.stream.get_mut().flush()?;
+        Ok(())
+    }
+
+    // the next message, pings are answered on the way and fragmented messages come back whole
+    // protocol errors of the peer close the connection with the matching code before being returned
+    pub fn receive(&mut self) -> Result<WsMessage, Box<dyn Error>> {
+        if self.close_received { Err(HandshakeError::Closed)? }
+
+        match self.read_message() {
+            Err(e) => {
+                if let Some(code) = e.downcast_ref::<CloseCode>() {
+                    if !self.close_sent {
+                        let _ = self.send_close(*code, "");
+                    }
+                }
+                Err(e)
+            }
+            message => message
+        }
+    }
+
+    fn read_message(&mut self) -> Result<WsMessage, Box<dyn Error>> {
+        loop {
+            let (frame, masked) = Frame::read(&mut self.stream, self.max_size)?;
+            // only clients mask, and only the first frame of a data message can say it's compressed
+            if masked == self.client { Err(CloseCode::ProtocolError)? }
+            if frame.rsv1 && (self.deflate.is_none() || frame.opcode.is_control() || frame.opcode == Opcode::Continuation) {
+                Err(CloseCode::ProtocolError)?
+            }
+
+            match frame.opcode {
+                Opcode::Ping => {
+                    if !self.close_sent {
+                        self.write(&Frame::new(Opcode::Pong, frame.payload.clone()))?;
+                        self.stream.get_mut().flush()?;
+                    }
+                    return Ok(WsMessage::Ping(frame.payload))
+                }
+                Opcode::Pong => return Ok(WsMessage::Pong(frame.payload)),
+                Opcode::Close => {
+                    self.close_received = true;
+                    let reason = close_reason(&frame.payload)?;
+                    if !self.close_sent {
+                        // echo the code back, which completes the closing handshake
+                        let code = reason.as_ref().map_or(CloseCode::Normal, |(code, _)| *code);
+                        self.send_close(code, "")?;
+                    }
+                    return Ok(WsMessage::Close(reason))
+                }
+                Opcode::Text | Opcode::Binary if self.fragments.is_some() => Err(CloseCode::ProtocolError)?,
+                Opcode::Text | Opcode::Binary if !frame.fin => self.fragments = Some((frame.opcode, frame.rsv1, frame.payload)),
+                Opcode::Text | Opcode::Binary => return self.message(frame.opcode, frame.rsv1, frame.payload),
+                Opcode::Continuation => {
+                    let (opcode, compressed, mut data) = self.fragments.take().ok_or(CloseCode::ProtocolError)?;
+                    if data.len() + frame.payload.len() > self.max_size { Err(CloseCode::TooBig)? }
+                    data.extend(frame.payload);
+                    if frame.fin { return self.message(opcode, compressed, data) }
+                    self.fragments = Some((opcode, compressed, data));
+                }
+            }
+        }
+    }
+
+    fn message(&mut self, opcode: Opcode, compressed: bool, data: Vec<u8>) -> Result<WsMessage, Box<dyn Error>> {
+        let data = match self.deflate.as_mut() {
+            Some(deflate) if compressed => deflate.decompress(&data, self.max_size)?,
+            _ => data
+        };
+
+        match opcode {
+            Opcode::Text => Ok(WsMessage::Text(String::from_utf8(data).or(Err(CloseCode::InvalidData))?)),
+            _ => Ok(WsMessage::Binary(data))
+        }
+    }
+}
+
+fn close_reason(payload: &[u8]) -> Result<Option<(CloseCode, String)>, CloseCode> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(CloseCode::ProtocolError),
+        _ => {
+            let code = CloseCode::parse(u16::from_be_bytes([payload[0], payload[1]]));
+            if !code.is_sendable() { Err(CloseCode::ProtocolError)? }
+            let reason = String::from_utf8(payload[2..].to_vec()).or(Err(CloseCode::InvalidData))?;
+            Ok(Some((code, reason)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn accept_key() {
+        assert_eq!(accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn exchange_messages() {
+        let (listener, port) = crate::tests::local();
+
+        let server = thread::spawn(move || {
+            let mut socket = listener.accept().unwrap().0;
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            let mut text = String::new();
+            let request = Request::parse(&mut reader, &mut text).unwrap();
+            let key = request.message.headers.get("sec-websocket-key").unwrap();
+
+            let mut response = Response::new();
+            response.status = Status::SwitchingProtocols;
+            response.message.headers.add(Header::new("Connection", "Upgrade"));
+            response.message.headers.add(Header::new("Upgrade", UPGRADE_WEBSOCKET));
+            response.message.headers.add(Header::new("Sec-WebSocket-Accept", accept(key)));
+            response.write_to(&mut socket).unwrap();
+
+            // echo everything back unfragmented until the client closes
+            let mut socket = WebSocket::new(reader, false);
+            loop {
+                match socket.receive().unwrap() {
+                    WsMessage::Close(_) => break,
+                    WsMessage::Ping(_) | WsMessage::Pong(_) => (),
+                    message => socket.send(message).unwrap()
+                }
+            }
+        });
+
+        let (mut client, _) = WebSocket::connect(Address::new("127.0.0.1", Some(port)), &mut Request::new()).unwrap();
+        client.set_fragment_size(Some(4));
+        client.send_text("fragmented text").unwrap();
+        assert_eq!(client.receive().unwrap(), WsMessage::Text("fragmented text".to_string()));
+        client.ping(b"ping").unwrap();
+        assert_eq!(client.receive().unwrap(), WsMessage::Pong(b"ping".to_vec()));
+        client.send_binary(&[0, 1, 2, 255]).unwrap();
+        assert_eq!(client.receive().unwrap(), WsMessage::Binary(vec![0, 1, 2, 255]));
+        client.close(CloseCode::Normal, "done").unwrap();
+        server.join().unwrap();
+    }
+
+    fn echo(request: Request) -> Option<Response> {
+        upgrade(&request, &["chat", "superchat"], true, |mut socket| {
+            while let Ok(message) = socket.receive() {
+                match message {
+                    WsMessage::Text(_) | WsMessage::Binary(_) => socket.send(message).unwrap(),
+                    WsMessage::Close(_) => break,
+                    _ => ()
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn upgrade_with_deflate() {
+        let (listener, port) = crate::tests::local();
+        Http11::listen_on(listener, &echo).unwrap();
+
+        let mut request = Request::new();
+        request.message.headers.add(Header::new("Sec-WebSocket-Protocol", "superchat, chat"));
+        request.message.headers.add(Header::new("Sec-WebSocket-Extensions", "permessage-deflate; client_max_window_bits"));
+        let (mut client, _) = WebSocket::connect(Address::new("127.0.0.1", Some(port)), &mut request).unwrap();
+        assert_eq!(client.protocol(), Some("chat"));
+        assert!(client.is_compressed());
+
+        // the compression context carries over between messages
+        let text = "compressible ".repeat(100);
+        client.set_fragment_size(Some(16));
+        for _ in 0..3 {
+            client.send_text(&text).unwrap();
+            assert_eq!(client.receive().unwrap(), WsMessage::Text(text.clone()));
+        }
+        client.close(CloseCode::Normal, "").unwrap();
+    }
+}